@@ -16,7 +16,7 @@ near create_account nfterc20contract.somenewname.testnet --masterAccount somenew
 ###
 #######
 near deploy --accountId nftcontract.somenewname.testnet --wasmFile ./res/non_fungible_token.wasm --initFunction new --initArgs '{"owner_id": "somenewname.testnet", "name": "nfts collectibles" , "symbol" : "platform" ,"base_uri": "somenewname"}'
-near deploy --accountId nftpoolcontract.somenewname.testnet --wasmFile ./res/nft_pool.wasm --initFunction new --initArgs '{"subowner" : "somenewname.testnet"}'
+near deploy --accountId nftpoolcontract.somenewname.testnet --wasmFile ./res/nft_pool.wasm --initFunction new --initArgs '{"operator_id" : "somenewname.testnet"}'
 near deploy --accountId nfterc20contract.somenewname.testnet --wasmFile ./res/fungible_token.wasm --initFunction new_default_meta --initArgs '{"owner_id":"somenewname.testnet","name":"WSK","total_supply":"20000000","nftcaller":"nftcontract.somenewname.testnet"}'
 
 #near deploy --accountId nftnewcontract.somenewname.testnet --wasmFile ./res/fungible_token.wasm --initFunction new_default_meta --initArgs '{"owner_id":"somenewname.testnet","name":"somenewname-pooltoken","total_supply":"20000000","nftcaller":"nftcontract.somenewname.testnet"}'
@@ -29,11 +29,20 @@ near call nfterc20contract.somenewname.testnet ft_transfer '{"receiver_id":"aman
 #####
 near call nftcontract.somenewname.testnet nft_mint '{"ipfs_hash":"https://cattery-api.amanraj.dev/api/img/6"}' --accountId somenewname.testnet --gas 300000000000000
 #near call nftcontract.somenewname.testnet invite_other '{"invitee":"amanraj1608.testnet"}' --accountId somenewname.testnet --gas 300000000000000
-#near call nftcontract.somenewname.testnet create_pool '{"pool_id":"nftpoolcontract.somenewname.testnet","roomsize":"200000000"}' --accountId somenewname.testnet --gas 300000000000000
+#near call nftcontract.somenewname.testnet nft_batch_mint '{"tokens":[["amanraj1608.testnet",{"title":"wow a boss cat","media":"https://cattery-api.amanraj.dev/api/img/7","copies":1}]],"class":"owner"}' --accountId somenewname.testnet --amount 0.1 --gas 300000000000000
+# the pool code is too big for a command line argument, so it is uploaded from the file (needs `npm install near-api-js`)
+node upload_pool_code.js nftpoolcontract.somenewname.testnet ./res/fungible_token.wasm 3
+near call nftpoolcontract.somenewname.testnet add_authorized_caller '{"account_id":"nftcontract.somenewname.testnet"}' --accountId nftpoolcontract.somenewname.testnet
+near call nftcontract.somenewname.testnet set_pool_factory '{"pool_factory":"nftpoolcontract.somenewname.testnet"}' --accountId somenewname.testnet
+#near call nftcontract.somenewname.testnet create_pool '{"pool_id":"nftpoolcontract.somenewname.testnet","roomsize":"200000000","metadata":{"spec":"ft-1.0.0","name":"somenewname room","symbol":"SNR","decimals":0}}' --accountId somenewname.testnet --amount 5.3 --gas 300000000000000
 #near call nftcontract.somenewname.testnet nft_mint '{"ipfs_hash":"https://avatars.githubusercontent.com/u/42795731?v=4"}' --accountId somenewname.testnet --gas 300000000000000
 #near call nftcontract.somenewname.testnet nft_mint '{"ipfs_hash":"https://avatars.githubusercontent.com/u/42104907?v=4"}' --accountId somenewname.testnet
 #near call nftcontract.somenewname.testnet invite_other '{"invitee":"amanraj1608.testnet"}' --accountId somenewname.testnet --gas 300000000000000
 #near call nftcontract.somenewname.testnet invite_other '{"invitee":"somenewname.testnet"}' -- accountId somenewname.testnet --gas 300000000000000
+#near call nfterc20contract.somenewname.testnet approve_nft_spending '{"amount":"1"}' --accountId amanraj1608.testnet --amount 0.01
+#near call nftcontract.somenewname.testnet accept_invite '{"offer_id":"0"}' --accountId amanraj1608.testnet --gas 300000000000000
+#near call nftcontract.somenewname.testnet decline_invite '{"offer_id":"0"}' --accountId amanraj1608.testnet
+#near call nftcontract.somenewname.testnet invite_with_key '{"public_key":"ed25519:..."}' --accountId somenewname.testnet --amount 1
 
 #near call nftcontract.somenewname.testnet nft_transfer '{"receiver_id":"testing2somenewname.testnet","token_id":"1"}' --accountId somenewname.testnet --depositYocto 1
 
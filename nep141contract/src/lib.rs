@@ -15,26 +15,192 @@ NOTES:
   - To prevent the deployed contract from being modified or deleted, it should not have any access
     keys on its account.
  */
+use std::collections::HashSet;
+
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds, StorageManagement};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LazyOption;
-use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{env, log, near_bindgen,ext_contract, AccountId, Balance, PanicOnDefault, PromiseOrValue};
-use near_sdk::PromiseOrValue::Promise;
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedSet};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen,ext_contract, AccountId, Balance, Gas, PanicOnDefault, Promise, PromiseOrValue, StorageUsage};
 
 near_sdk::setup_alloc!();
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
+    owner_id: AccountId,
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
-    nftcallerall: AccountId
+    nftcallerall: AccountId,
+    /// Account that initialized this contract, i.e. the pool factory for pool tokens.
+    factory: AccountId,
+    /// How much of an owner's balance a spender may still move, keyed by (owner, spender).
+    /// `nft_internal_transfer` spends the allowance given to `nftcallerall`.
+    allowances: LookupMap<(AccountId, AccountId), Balance>,
+    /// Staked tokens are held on this contract's own account and tracked here per staker.
+    stakes: LookupMap<AccountId, Stake>,
+    total_staked: Balance,
+    /// Number of entries in `stakes`; a pool can only be closed once it is 0.
+    stake_records: u64,
+    /// Nanoseconds between `unstake` and `withdraw_unstaked`.
+    unstake_cooldown: u64,
+    /// Rewards deposited per staked token so far.
+    near_reward_per_token: RewardPerToken,
+    ft_reward_per_token: RewardPerToken,
+    /// Accounts besides the owner that may call `ft_mint`.
+    minters: UnorderedSet<AccountId>,
+    /// Hard cap on the total supply for `ft_mint`, if any.
+    max_supply: Option<Balance>,
+    /// Registered accounts that meet the membership requirement, as of their last balance or
+    /// stake change. The storage deposit of every account covers its entry here.
+    members: UnorderedSet<AccountId>,
+    /// An account is a member once its tokens, liquid, staked or unstaking, reach
+    /// `member_min_balance` or its stake alone reaches `member_min_stake`, if set.
+    member_min_balance: Option<Balance>,
+    member_min_stake: Option<Balance>,
+    /// Tokens vesting to a beneficiary, held on this contract's own account until claimed.
+    vestings: LookupMap<AccountId, Vesting>,
+    /// Fee on `ft_transfer` and `ft_transfer_call` in basis points, paid to `fee_recipient`.
+    transfer_fee_bps: u16,
+    fee_recipient: AccountId,
+    /// Transfers from or to these accounts pay no fee.
+    fee_exempt: UnorderedSet<AccountId>,
+    /// Id of the latest `snapshot`, 0 before the first one.
+    current_snapshot_id: u64,
+    /// Snapshot balances and total supply as they were at the last `MAX_SNAPSHOT_HISTORY`
+    /// snapshots. Only the first change after a snapshot writes a checkpoint; unchanged values
+    /// are read from the live state. The storage deposit of every account covers its entry,
+    /// which stays behind when the account is closed.
+    balance_checkpoints: LookupMap<AccountId, Checkpoints>,
+    supply_checkpoints: Checkpoints,
+    /// Number of registered accounts, to keep `close` from sweeping their storage deposits.
+    registered_accounts: u64,
+}
+
+/// Values before their first change after each snapshot, ordered by snapshot id. Only
+/// checkpoints of the last `MAX_SNAPSHOT_HISTORY` snapshots are kept.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Checkpoints {
+    last_snapshot_id: u64,
+    values: Vec<(u64, Balance)>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Stake {
+    staked: Balance,
+    /// Unstaked tokens waiting for the cooldown, withdrawable from `unstake_available_at`.
+    unstaking: Balance,
+    unstake_available_at: u64,
+    /// Reward per token already accounted for in `near_rewards` and `ft_rewards`.
+    near_reward_per_token_paid: RewardPerToken,
+    ft_reward_per_token_paid: RewardPerToken,
+    /// Rewards earned but not claimed yet.
+    near_rewards: Balance,
+    ft_rewards: Balance,
+}
+
+/// A reward per staked token of `whole + frac / REWARD_PRECISION`. Keeping the whole part
+/// apart lets large deposits over a small stake add up without overflowing.
+#[derive(BorshDeserialize, BorshSerialize, Default, Clone, Copy)]
+pub struct RewardPerToken {
+    whole: u128,
+    frac: u128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeInfo {
+    pub staked: U128,
+    pub unstaking: U128,
+    pub unstake_available_at: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingRewards {
+    pub near: U128,
+    pub ft: U128,
+}
+
+/// `amount` vests linearly from `start` to `end`, but nothing can be claimed before `cliff`.
+/// All three are block timestamps in nanoseconds.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingSchedule {
+    pub amount: U128,
+    pub start: U64,
+    pub cliff: U64,
+    pub end: U64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Vesting {
+    total: Balance,
+    claimed: Balance,
+    start: u64,
+    cliff: u64,
+    end: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingInfo {
+    pub total: U128,
+    pub vested: U128,
+    pub unvested: U128,
+    pub claimed: U128,
+    pub claimable: U128,
+    pub start: U64,
+    pub cliff: U64,
+    pub end: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransferFee {
+    pub fee_bps: u16,
+    pub fee_recipient: AccountId,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MembershipRequirement {
+    pub min_balance: Option<U128>,
+    pub min_stake: Option<U128>,
+}
+
+const GAS_FOR_MIGRATE: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 5_000_000_000_000;
+const GAS_FOR_FT_TRANSFER_CALL: Gas = 25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER;
+/// Scale of the fractional part of `RewardPerToken`, so small deposits over a large stake still count.
+const REWARD_PRECISION: u128 = 1_000_000_000_000_000_000_000_000;
+/// Highest transfer fee the owner can set, 10%.
+const MAX_TRANSFER_FEE_BPS: u16 = 1_000;
+/// How many of the latest snapshots `balance_of_at` and `total_supply_at` can answer for.
+const MAX_SNAPSHOT_HISTORY: u64 = 10;
+/// Event standard of the NEP-141 `ft_mint`, `ft_burn` and `ft_transfer` events.
+const NEP141_STANDARD: &str = "nep141";
+/// Event standard of the events NEP-141 does not define, e.g. `ft_approve` and `member_added`.
+const POOL_TOKEN_STANDARD: &str = "nftpool_token";
+
+
+#[ext_contract(ext_ft_receiver)]
+pub trait FungibleTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
 }
 
+#[ext_contract(ext_self)]
+pub trait FungibleTokenResolverCallback {
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128;
+}
 
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 288 288'%3E%3Cg id='l' data-name='l'%3E%3Cpath d='M187.58,79.81l-30.1,44.69a3.2,3.2,0,0,0,4.75,4.2L191.86,103a1.2,1.2,0,0,1,2,.91v80.46a1.2,1.2,0,0,1-2.12.77L102.18,77.93A15.35,15.35,0,0,0,90.47,72.5H87.34A15.34,15.34,0,0,0,72,87.84V201.16A15.34,15.34,0,0,0,87.34,216.5h0a15.35,15.35,0,0,0,13.08-7.31l30.1-44.69a3.2,3.2,0,0,0-4.75-4.2L96.14,186a1.2,1.2,0,0,1-2-.91V104.61a1.2,1.2,0,0,1,2.12-.77l89.55,107.23a15.35,15.35,0,0,0,11.71,5.43h3.13A15.34,15.34,0,0,0,216,201.16V87.84A15.34,15.34,0,0,0,200.66,72.5h0A15.35,15.35,0,0,0,187.58,79.81Z'/%3E%3C/g%3E%3C/svg%3E";
 
@@ -49,7 +215,7 @@ impl Contract {
             owner_id,
             total_supply,
             FungibleTokenMetadata {
-                spec: "ft-1.0.0".to_string(),
+                spec: FT_METADATA_SPEC.to_string(),
                 name: name.clone(),
                 symbol: name.clone(),
                 icon: Some(DATA_IMAGE_SVG_NEAR_ICON.to_string()),
@@ -57,55 +223,1062 @@ impl Contract {
                 reference_hash: None,
                 decimals: 1,
             },
-            nftcaller
+            nftcaller,
+            None
         )
     }
 
     /// Initializes the contract with the given total supply owned by the given `owner_id` with
-    /// the given fungible token metadata.
+    /// the given fungible token metadata. With `owner_vesting`, that part of the supply vests
+    /// to `owner_id` instead of being liquid right away.
     #[init]
     pub fn new(
         owner_id: ValidAccountId,
         total_supply: U128,
         metadata: FungibleTokenMetadata,
-        nftcaller : AccountId
+        nftcaller : AccountId,
+        owner_vesting: Option<VestingSchedule>
     )  ->Self{
         assert!(!env::state_exists(), "Already initialized");
         metadata.assert_valid();
         let mut this = Self {
+            owner_id: owner_id.clone().into(),
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
-            nftcallerall: nftcaller
+            nftcallerall: nftcaller,
+            factory: env::predecessor_account_id(),
+            allowances: LookupMap::new(b"n".to_vec()),
+            stakes: LookupMap::new(b"s".to_vec()),
+            total_staked: 0,
+            stake_records: 0,
+            unstake_cooldown: 0,
+            near_reward_per_token: RewardPerToken::default(),
+            ft_reward_per_token: RewardPerToken::default(),
+            minters: UnorderedSet::new(b"t".to_vec()),
+            max_supply: None,
+            members: UnorderedSet::new(b"e".to_vec()),
+            member_min_balance: Some(1),
+            member_min_stake: None,
+            vestings: LookupMap::new(b"l".to_vec()),
+            transfer_fee_bps: 0,
+            fee_recipient: owner_id.clone().into(),
+            fee_exempt: UnorderedSet::new(b"x".to_vec()),
+            current_snapshot_id: 0,
+            balance_checkpoints: LookupMap::new(b"h".to_vec()),
+            supply_checkpoints: Checkpoints::default(),
+            registered_accounts: 0,
         };
-        this.token.internal_register_account(owner_id.as_ref());
+        this.fee_exempt.insert(&this.nftcallerall);
+        this.fee_exempt.insert(&this.factory);
+        this.token.account_storage_usage += this.measure_member_storage_usage() + this.measure_checkpoint_storage_usage();
+        this.internal_register_account(owner_id.as_ref());
         this.token.internal_deposit(owner_id.as_ref(), total_supply.into());
+        emit_event(NEP141_STANDARD, "ft_mint", vec![json!({
+            "owner_id": owner_id,
+            "amount": total_supply,
+            "memo": "new",
+        })]);
+        this.internal_update_membership(owner_id.as_ref());
+        if let Some(schedule) = owner_vesting {
+            this.internal_add_vesting(owner_id.as_ref(), schedule);
+        }
 this
     }
 
+    /// Replaces the code of this contract with the raw input of this call and runs `migrate`
+    /// on the new code. Only the factory that deployed this pool can call this.
+    pub fn upgrade(&self) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.factory, "only the factory can upgrade this pool");
+        let code = env::input().expect("expected code as input");
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(b"migrate".to_vec(), b"{}".to_vec(), 0, GAS_FOR_MIGRATE)
+    }
+
+    /// Called by `upgrade` after the new code is deployed. Every deployed pool has the current
+    /// layout, so this only reads it back; update it when the state layout changes between
+    /// versions.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        env::state_read().expect("failed to read the old state")
+    }
+
+    /// Deletes this pool account and sends its balance to `beneficiary_id`. Only the factory
+    /// can call this, and only once the whole supply is back with `owner_id` or burned, every
+    /// staker has withdrawn their stake and claimed their rewards, and every other holder has
+    /// unregistered and got their storage deposit back.
+    pub fn close(&mut self, owner_id: AccountId, beneficiary_id: AccountId) -> Promise {
+        assert_eq!(env::predecessor_account_id(), self.factory, "only the factory can close this pool");
+        assert_eq!(self.stake_records, 0, "stakes, unstaked tokens or rewards are still waiting to be withdrawn");
+        let total_supply = self.token.total_supply;
+        assert!(
+            total_supply == 0 || self.token.accounts.get(&owner_id).unwrap_or(0) == total_supply,
+            "the whole supply must be returned to {} or burned first",
+            owner_id
+        );
+        // The owner, this contract and the NFT contract are registered without a deposit.
+        let current_account_id = env::current_account_id();
+        let contract_paid_registered: HashSet<&AccountId> = [&owner_id, &current_account_id, &self.nftcallerall]
+            .iter()
+            .copied()
+            .filter(|account_id| self.token.accounts.contains_key(account_id))
+            .collect();
+        assert_eq!(
+            self.registered_accounts,
+            contract_paid_registered.len() as u64,
+            "registered accounts have to unregister before the pool can be closed"
+        );
+        Promise::new(env::current_account_id()).delete_account(beneficiary_id)
+    }
+
+    /// The checkpoints of a closed account stay, so its balance at past snapshots still adds
+    /// up to `total_supply_at`; `storage_unregister` keeps back their storage cost.
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
-        log!("Closed @{} with {}", account_id, balance);
+        self.registered_accounts -= 1;
+        self.internal_record_total_supply(self.token.total_supply + balance);
+        self.internal_update_membership(&account_id);
+        if balance > 0 {
+            self.on_tokens_burned(account_id, balance, "storage_unregister");
+        }
     }
 
-    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
-        log!("Account @{} burned {}", account_id, amount);
+    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance, memo: &str) {
+        emit_event(NEP141_STANDARD, "ft_burn", vec![json!({
+            "owner_id": account_id,
+            "amount": U128(amount),
+            "memo": memo,
+        })]);
     }
 
-    fn stake_inpool(&mut self, amount: U128){
+    /// Creates `amount` new tokens for `account_id`, which must be registered. Only the owner
+    /// or a minter can call this, and not beyond `max_supply`.
+    pub fn ft_mint(&mut self, account_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        self.assert_minter();
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        let total_supply = self.token.total_supply.checked_add(amount.0).expect("Total supply overflow");
+        if let Some(max_supply) = self.max_supply {
+            assert!(total_supply <= max_supply, "minting would exceed the max supply of {}", max_supply);
+        }
+        self.internal_checkpoint(account_id.as_ref());
+        self.internal_record_total_supply(self.token.total_supply);
+        self.token.internal_deposit(account_id.as_ref(), amount.0);
+        self.internal_update_membership(account_id.as_ref());
+        emit_event(NEP141_STANDARD, "ft_mint", vec![json!({
+            "owner_id": account_id,
+            "amount": amount,
+            "memo": memo,
+        })]);
+    }
+
+    /// Destroys `amount` of the caller's tokens. Requires exactly 1 yoctoNEAR.
+    #[payable]
+    pub fn ft_burn(&mut self, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        let account_id = env::predecessor_account_id();
+        self.internal_checkpoint(&account_id);
+        self.internal_record_total_supply(self.token.total_supply);
+        self.token.internal_withdraw(&account_id, amount.0);
+        self.internal_update_membership(&account_id);
+        emit_event(NEP141_STANDARD, "ft_burn", vec![json!({
+            "owner_id": account_id,
+            "amount": amount,
+            "memo": memo,
+        })]);
+    }
+
+    /// Sets the hard cap on the total supply, or removes it with `None`. The cap can't be
+    /// below the current supply.
+    pub fn set_max_supply(&mut self, max_supply: Option<U128>) {
+        self.assert_owner();
+        if let Some(max_supply) = max_supply {
+            assert!(max_supply.0 >= self.token.total_supply, "the max supply is below the current supply");
+        }
+        self.max_supply = max_supply.map(|max_supply| max_supply.0);
+    }
+
+    pub fn get_max_supply(&self) -> Option<U128> {
+        self.max_supply.map(U128)
+    }
+
+    pub fn add_minter(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.minters.insert(account_id.as_ref())
+    }
+
+    pub fn remove_minter(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.minters.remove(account_id.as_ref())
+    }
+
+    pub fn get_minters(&self) -> Vec<AccountId> {
+        self.minters.to_vec()
+    }
+
+    /// Moves `amount` of the caller's liquid balance into their stake. Requires at least
+    /// 1 yoctoNEAR; the first stake also has to cover the storage of the stake record.
+    #[payable]
+    pub fn stake(&mut self, amount: U128) {
+        assert!(env::attached_deposit() >= 1, "Requires attached deposit of at least 1 yoctoNEAR");
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
         let contract_id = env::current_account_id();
-        let memo : String = format!("staked in pool {} by {}",contract_id.clone(),env::predecessor_account_id());
-        let reciever_id=ValidAccountId::try_from(env::current_account_id()).unwrap();
-        self.token.ft_transfer(reciever_id,amount,Some(memo))
+        if !self.token.accounts.contains_key(&contract_id) {
+            self.internal_register_account(&contract_id);
+        }
+        // The stake is saved before the transfer, so moving the whole balance does not
+        // drop the account out of `members` on the way.
+        let mut stake = self.stakes.get(&account_id).unwrap_or_default();
+        self.internal_update_rewards(&mut stake);
+        stake.staked += amount.0;
+        self.internal_save_stake(&account_id, stake);
+        self.total_staked += amount.0;
+        self.internal_transfer(&account_id, &contract_id, amount.0, Some("stake".to_string()));
+        self.internal_update_membership(&account_id);
+        log!("@{} staked {}", account_id, amount.0);
+        settle_storage_deposit(initial_storage_usage);
+    }
+
+    /// Takes `amount` out of the caller's stake. Without a cooldown it is liquid right away,
+    /// otherwise it can be withdrawn with `withdraw_unstaked` once the cooldown has passed.
+    /// Unstaking again restarts the cooldown for everything still unstaking.
+    #[payable]
+    pub fn unstake(&mut self, amount: U128) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.internal_checkpoint(&account_id);
+        let mut stake = self.stakes.get(&account_id).expect("nothing staked");
+        assert!(amount.0 > 0 && amount.0 <= stake.staked, "amount exceeds the staked balance");
+        self.internal_update_rewards(&mut stake);
+        stake.staked -= amount.0;
+        self.total_staked -= amount.0;
+        log!("@{} unstaked {}", account_id, amount.0);
+
+        if self.unstake_cooldown == 0 {
+            self.internal_save_stake(&account_id, stake);
+            self.internal_transfer(&env::current_account_id(), &account_id, amount.0, Some("unstake".to_string()));
+        } else {
+            stake.unstaking += amount.0;
+            stake.unstake_available_at = env::block_timestamp() + self.unstake_cooldown;
+            self.internal_save_stake(&account_id, stake);
+        }
+        self.internal_update_membership(&account_id);
+    }
+
+    /// Moves the caller's unstaked tokens back to their liquid balance after the cooldown.
+    #[payable]
+    pub fn withdraw_unstaked(&mut self) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.internal_checkpoint(&account_id);
+        let mut stake = self.stakes.get(&account_id).expect("nothing staked");
+        assert!(stake.unstaking > 0, "nothing to withdraw");
+        assert!(env::block_timestamp() >= stake.unstake_available_at, "the unstake cooldown has not passed yet");
+        let amount = stake.unstaking;
+        stake.unstaking = 0;
+        self.internal_save_stake(&account_id, stake);
+        self.internal_transfer(&env::current_account_id(), &account_id, amount, Some("withdraw unstaked".to_string()));
+    }
+
+    pub fn set_unstake_cooldown(&mut self, cooldown: U64) {
+        self.assert_owner();
+        self.unstake_cooldown = cooldown.0;
+    }
+
+    pub fn get_unstake_cooldown(&self) -> U64 {
+        U64(self.unstake_cooldown)
+    }
+
+    pub fn get_stake(&self, account_id: ValidAccountId) -> StakeInfo {
+        let stake = self.stakes.get(account_id.as_ref()).unwrap_or_default();
+        StakeInfo {
+            staked: U128(stake.staked),
+            unstaking: U128(stake.unstaking),
+            unstake_available_at: U64(stake.unstake_available_at),
+        }
+    }
+
+    pub fn staked_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.stakes.get(account_id.as_ref()).map(|stake| stake.staked).unwrap_or(0))
+    }
+
+    pub fn total_staked(&self) -> U128 {
+        U128(self.total_staked)
+    }
+
+    /// Splits the attached NEAR between the current stakers, pro rata to their stake.
+    #[payable]
+    pub fn deposit_near_rewards(&mut self) {
+        self.assert_owner();
+        let amount = env::attached_deposit();
+        assert!(amount > 0, "Requires a positive attached deposit");
+        self.near_reward_per_token.add(amount, self.total_staked);
+        log!("@{} deposited {} yoctoNEAR of rewards", self.owner_id, amount);
+    }
+
+    /// Splits `amount` of the owner's tokens between the current stakers, pro rata to their stake.
+    #[payable]
+    pub fn deposit_ft_rewards(&mut self, amount: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        self.ft_reward_per_token.add(amount.0, self.total_staked);
+        let owner_id = self.owner_id.clone();
+        self.internal_transfer(&owner_id, &env::current_account_id(), amount.0, Some("staking rewards".to_string()));
+        log!("@{} deposited {} of rewards", owner_id, amount.0);
+    }
+
+    /// Pays out the caller's NEAR and token rewards. Rounding dust stays on the contract.
+    #[payable]
+    pub fn claim_rewards(&mut self) -> PendingRewards {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.internal_checkpoint(&account_id);
+        let mut stake = self.stakes.get(&account_id).expect("nothing staked");
+        self.internal_update_rewards(&mut stake);
+        let near = std::mem::take(&mut stake.near_rewards);
+        let ft = std::mem::take(&mut stake.ft_rewards);
+        self.internal_save_stake(&account_id, stake);
+
+        if ft > 0 {
+            self.internal_transfer(&env::current_account_id(), &account_id, ft, Some("staking rewards".to_string()));
+        }
+        if near > 0 {
+            Promise::new(account_id.clone()).transfer(near);
+        }
+        log!("@{} claimed {} yoctoNEAR and {} of rewards", account_id, near, ft);
+        PendingRewards { near: U128(near), ft: U128(ft) }
+    }
+
+    pub fn pending_rewards(&self, account_id: ValidAccountId) -> PendingRewards {
+        let mut stake = self.stakes.get(account_id.as_ref()).unwrap_or_default();
+        self.internal_update_rewards(&mut stake);
+        PendingRewards {
+            near: U128(stake.near_rewards),
+            ft: U128(stake.ft_rewards),
+        }
+    }
+
+    /// Moves `schedule.amount` of the owner's tokens into a vesting schedule for `beneficiary_id`,
+    /// who can't have another one running. The attached deposit must cover the storage.
+    #[payable]
+    pub fn add_vesting(&mut self, beneficiary_id: ValidAccountId, schedule: VestingSchedule) {
+        self.assert_owner();
+        let initial_storage_usage = env::storage_usage();
+        self.internal_add_vesting(beneficiary_id.as_ref(), schedule);
+        settle_storage_deposit(initial_storage_usage);
+    }
+
+    /// Moves the caller's vested but unclaimed tokens to their balance.
+    #[payable]
+    pub fn claim_vested(&mut self) -> U128 {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.internal_checkpoint(&account_id);
+        let mut vesting = self.vestings.get(&account_id).expect("no vesting schedule");
+        let claimable = vesting.vested(env::block_timestamp()) - vesting.claimed;
+        assert!(claimable > 0, "nothing to claim yet");
+        vesting.claimed += claimable;
+        if vesting.claimed == vesting.total {
+            self.vestings.remove(&account_id);
+        } else {
+            self.vestings.insert(&account_id, &vesting);
+        }
+        self.internal_transfer(&env::current_account_id(), &account_id, claimable, Some("vesting".to_string()));
+        U128(claimable)
+    }
+
+    pub fn get_vesting(&self, account_id: ValidAccountId) -> Option<VestingInfo> {
+        self.vestings.get(account_id.as_ref()).map(|vesting| {
+            let vested = vesting.vested(env::block_timestamp());
+            VestingInfo {
+                total: U128(vesting.total),
+                vested: U128(vested),
+                unvested: U128(vesting.total - vested),
+                claimed: U128(vesting.claimed),
+                claimable: U128(vested - vesting.claimed),
+                start: U64(vesting.start),
+                cliff: U64(vesting.cliff),
+                end: U64(vesting.end),
+            }
+        })
+    }
+
+    /// Records the current balances and total supply under a new snapshot id and returns it.
+    /// The snapshot balance of an account counts its staked, unstaking and unclaimed vesting
+    /// tokens too, so the contract's own account, which holds those, has none.
+    pub fn snapshot(&mut self) -> U64 {
+        self.assert_owner();
+        self.current_snapshot_id += 1;
+        log!("Snapshot {}", self.current_snapshot_id);
+        U64(self.current_snapshot_id)
+    }
+
+    pub fn get_current_snapshot_id(&self) -> U64 {
+        U64(self.current_snapshot_id)
+    }
+
+    pub fn balance_of_at(&self, account_id: ValidAccountId, snapshot_id: U64) -> U128 {
+        self.assert_snapshot_exists(snapshot_id.0);
+        let balance = self
+            .balance_checkpoints
+            .get(account_id.as_ref())
+            .and_then(|checkpoints| checkpoints.value_at(snapshot_id.0))
+            .unwrap_or_else(|| self.internal_snapshot_balance(account_id.as_ref()));
+        U128(balance)
+    }
+
+    pub fn total_supply_at(&self, snapshot_id: U64) -> U128 {
+        self.assert_snapshot_exists(snapshot_id.0);
+        U128(self.supply_checkpoints.value_at(snapshot_id.0).unwrap_or(self.token.total_supply))
+    }
+
+    /// Sets the fee on `ft_transfer`, `ft_transfer_call`, `ft_transfer_from` and
+    /// `nft_internal_transfer`, at most `MAX_TRANSFER_FEE_BPS`.
+    pub fn set_transfer_fee(&mut self, fee_bps: u16) {
+        self.assert_owner();
+        assert!(fee_bps <= MAX_TRANSFER_FEE_BPS, "the transfer fee can be at most {} basis points", MAX_TRANSFER_FEE_BPS);
+        self.transfer_fee_bps = fee_bps;
+        log!("Transfer fee set to {} basis points", fee_bps);
+    }
+
+    /// Sends future transfer fees to `account_id`, which must be registered.
+    pub fn set_fee_recipient(&mut self, account_id: ValidAccountId) {
+        self.assert_owner();
+        self.token.internal_unwrap_balance_of(account_id.as_ref());
+        self.fee_recipient = account_id.into();
+    }
+
+    pub fn get_transfer_fee(&self) -> TransferFee {
+        TransferFee {
+            fee_bps: self.transfer_fee_bps,
+            fee_recipient: self.fee_recipient.clone(),
+        }
+    }
+
+    pub fn add_fee_exempt(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.fee_exempt.insert(account_id.as_ref())
+    }
+
+    pub fn remove_fee_exempt(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.fee_exempt.remove(account_id.as_ref())
+    }
+
+    pub fn get_fee_exempt(&self) -> Vec<AccountId> {
+        self.fee_exempt.to_vec()
     }
 
+    /// Sets the holdings and stake that make an account a member; `None` disables that path.
+    /// Staked and unstaking tokens count toward `min_balance`.
+    /// Accounts are re-checked on their next balance or stake change, or with `refresh_membership`.
+    pub fn set_membership_requirement(&mut self, min_balance: Option<U128>, min_stake: Option<U128>) {
+        self.assert_owner();
+        assert!(
+            !matches!(min_balance, Some(U128(0))) && !matches!(min_stake, Some(U128(0))),
+            "membership requirements must be positive"
+        );
+        self.member_min_balance = min_balance.map(|min| min.0);
+        self.member_min_stake = min_stake.map(|min| min.0);
+    }
+
+    pub fn get_membership_requirement(&self) -> MembershipRequirement {
+        MembershipRequirement {
+            min_balance: self.member_min_balance.map(U128),
+            min_stake: self.member_min_stake.map(U128),
+        }
+    }
+
+    /// Re-checks the membership of `account_ids`, e.g. after the requirement changed.
+    pub fn refresh_membership(&mut self, account_ids: Vec<ValidAccountId>) {
+        for account_id in account_ids {
+            self.internal_update_membership(account_id.as_ref());
+        }
+    }
+
+    pub fn is_member(&self, account_id: ValidAccountId) -> bool {
+        self.internal_is_member(account_id.as_ref())
+    }
+
+    pub fn members(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<AccountId> {
+        let values = self.members.as_vector();
+        let start = from_index.map(|index| index.0).unwrap_or(0);
+        let end = start.saturating_add(limit.unwrap_or(u64::MAX)).min(values.len());
+        (start..end).filter_map(|index| values.get(index)).collect()
+    }
+
+    pub fn member_count(&self) -> U64 {
+        U64(self.members.len())
+    }
+
+    /// Lets the NFT contract move up to `amount` of the caller's tokens with
+    /// `nft_internal_transfer`, e.g. to pay for invites. Same as `ft_approve` for `nftcallerall`.
+    #[payable]
+    pub fn approve_nft_spending(&mut self, amount: U128) {
+        let spender_id = ValidAccountId::try_from(self.nftcallerall.clone()).unwrap();
+        self.ft_approve(spender_id, amount)
+    }
+
+    pub fn nft_allowance(&self, account_id: ValidAccountId) -> U128 {
+        U128(self.internal_allowance(account_id.as_ref(), &self.nftcallerall))
+    }
+
+    /// Moves `amount` from `invitee` to the NFT contract, within the allowance `invitee` gave
+    /// with `approve_nft_spending`. Only the NFT contract can call this.
     pub fn nft_internal_transfer(&mut self, invitee: AccountId, amount : U128){
-        if self.token.accounts.insert(&env::predecessor_account_id().clone(), &0).is_some() {}
-        assert_eq!(env::predecessor_account_id(),self.nftcallerall);
-        self.token.internal_transfer(&invitee, &env::predecessor_account_id(),amount.0, None)
+        let nft_contract = env::predecessor_account_id();
+        assert_eq!(nft_contract, self.nftcallerall, "only the nft contract can call this method");
+        self.internal_spend_allowance(&invitee, &nft_contract, amount.0);
+        if !self.token.accounts.contains_key(&nft_contract) {
+            self.internal_register_account(&nft_contract);
+        }
+        self.internal_transfer_with_fee(&invitee, &nft_contract, amount.0, None);
+    }
+
+    /// Lets `spender_id` move up to `amount` of the caller's tokens with `ft_transfer_from`.
+    /// Replaces any earlier allowance for `spender_id`; 0 revokes it. The attached deposit must
+    /// be at least 1 yoctoNEAR and cover the storage of the allowance; the rest is refunded.
+    #[payable]
+    pub fn ft_approve(&mut self, spender_id: ValidAccountId, amount: U128) {
+        assert!(env::attached_deposit() >= 1, "Requires attached deposit of at least 1 yoctoNEAR");
+        let initial_storage_usage = env::storage_usage();
+        let owner_id = env::predecessor_account_id();
+        self.token.internal_unwrap_balance_of(&owner_id);
+        let key = (owner_id.clone(), spender_id.to_string());
+        if amount.0 == 0 {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &amount.0);
+        }
+        emit_event(POOL_TOKEN_STANDARD, "ft_approve", vec![json!({
+            "owner_id": owner_id,
+            "spender_id": spender_id,
+            "amount": amount,
+        })]);
+        settle_storage_deposit(initial_storage_usage);
+    }
+
+    /// Removes the allowance of `spender_id` and refunds its storage. Requires at least
+    /// 1 yoctoNEAR, like `ft_approve`.
+    #[payable]
+    pub fn ft_revoke(&mut self, spender_id: ValidAccountId) {
+        self.ft_approve(spender_id, U128(0))
+    }
+
+    pub fn ft_allowance(&self, owner_id: ValidAccountId, spender_id: ValidAccountId) -> U128 {
+        U128(self.internal_allowance(owner_id.as_ref(), spender_id.as_ref()))
+    }
+
+    /// Moves `amount` from `owner_id` to `receiver_id` within the allowance `owner_id` gave the
+    /// caller. Like `ft_transfer`, requires exactly 1 yoctoNEAR.
+    #[payable]
+    pub fn ft_transfer_from(&mut self, owner_id: ValidAccountId, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let spender_id = env::predecessor_account_id();
+        self.internal_spend_allowance(owner_id.as_ref(), &spender_id, amount.0);
+        self.internal_transfer_with_fee(owner_id.as_ref(), receiver_id.as_ref(), amount.0, memo);
+    }
+}
+
+impl Contract {
+    fn assert_owner(&self) {
+        assert_eq!(env::predecessor_account_id(), self.owner_id, "only the pool owner can call this method");
+    }
+
+    fn assert_minter(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || self.minters.contains(&caller),
+            "only the pool owner or a minter can call this method"
+        );
+    }
+
+    /// Saves `stake`, or drops the record once nothing is staked, unstaking or left to claim.
+    fn internal_save_stake(&mut self, account_id: &AccountId, stake: Stake) {
+        if stake.staked == 0 && stake.unstaking == 0 && stake.near_rewards == 0 && stake.ft_rewards == 0 {
+            if self.stakes.remove(account_id).is_some() {
+                self.stake_records -= 1;
+            }
+        } else if self.stakes.insert(account_id, &stake).is_none() {
+            self.stake_records += 1;
+        }
+    }
+
+    /// Adds what `stake` earned since it was last updated to its unclaimed rewards. Must run
+    /// before every change of `stake.staked`.
+    fn internal_update_rewards(&self, stake: &mut Stake) {
+        stake.near_rewards += self.near_reward_per_token.earned_since(&stake.near_reward_per_token_paid, stake.staked);
+        stake.ft_rewards += self.ft_reward_per_token.earned_since(&stake.ft_reward_per_token_paid, stake.staked);
+        stake.near_reward_per_token_paid = self.near_reward_per_token;
+        stake.ft_reward_per_token_paid = self.ft_reward_per_token;
+    }
+
+    fn internal_add_vesting(&mut self, beneficiary_id: &AccountId, schedule: VestingSchedule) {
+        assert!(schedule.amount.0 > 0, "The amount should be a positive number");
+        assert!(
+            schedule.start.0 <= schedule.cliff.0 && schedule.cliff.0 <= schedule.end.0 && schedule.start.0 < schedule.end.0,
+            "vesting needs start <= cliff <= end and start < end"
+        );
+        assert!(self.vestings.get(beneficiary_id).is_none(), "{} already has a vesting schedule", beneficiary_id);
+        let owner_id = self.owner_id.clone();
+        self.internal_checkpoint(&owner_id);
+        self.internal_checkpoint(beneficiary_id);
+        self.vestings.insert(beneficiary_id, &Vesting {
+            total: schedule.amount.0,
+            claimed: 0,
+            start: schedule.start.0,
+            cliff: schedule.cliff.0,
+            end: schedule.end.0,
+        });
+        let contract_id = env::current_account_id();
+        if !self.token.accounts.contains_key(&contract_id) {
+            self.internal_register_account(&contract_id);
+        }
+        self.internal_transfer(&owner_id, &contract_id, schedule.amount.0, Some(format!("vesting for {}", beneficiary_id)));
+    }
+
+    /// Transfers `amount` minus the transfer fee to `receiver_id` and the fee to the fee
+    /// recipient, each logged as its own `ft_transfer`. Returns what `receiver_id` got.
+    /// Like the `fee_exempt` accounts, the fee recipient never pays or charges a fee.
+    fn internal_transfer_with_fee(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: Option<String>) -> Balance {
+        let exempt = |account_id: &AccountId| *account_id == self.fee_recipient || self.fee_exempt.contains(account_id);
+        let fee = if exempt(sender_id) || exempt(receiver_id) {
+            0
+        } else {
+            mul_div(amount, u128::from(self.transfer_fee_bps), 10_000)
+        };
+        self.internal_transfer(sender_id, receiver_id, amount - fee, memo);
+        if fee > 0 {
+            let fee_recipient = self.fee_recipient.clone();
+            self.internal_transfer(sender_id, &fee_recipient, fee, Some("transfer fee".to_string()));
+        }
+        amount - fee
+    }
+
+    fn assert_snapshot_exists(&self, snapshot_id: u64) {
+        assert!(snapshot_id > 0 && snapshot_id <= self.current_snapshot_id, "snapshot {} does not exist", snapshot_id);
+        assert!(
+            snapshot_id + MAX_SNAPSHOT_HISTORY > self.current_snapshot_id,
+            "snapshot {} is too old, only the last {} snapshots are kept",
+            snapshot_id,
+            MAX_SNAPSHOT_HISTORY
+        );
+    }
+
+    /// The liquid, staked, unstaking and unclaimed vesting tokens of `account_id`.
+    fn internal_snapshot_balance(&self, account_id: &AccountId) -> Balance {
+        if *account_id == env::current_account_id() {
+            return 0;
+        }
+        let balance = self.token.accounts.get(account_id).unwrap_or(0);
+        let staked = self.stakes.get(account_id).map(|stake| stake.staked + stake.unstaking).unwrap_or(0);
+        let vesting = self.vestings.get(account_id).map(|vesting| vesting.total - vesting.claimed).unwrap_or(0);
+        balance + staked + vesting
+    }
+
+    /// Must run before any change to the balance, stake or vesting of `account_id`.
+    fn internal_checkpoint(&mut self, account_id: &AccountId) {
+        if self.current_snapshot_id == 0 || *account_id == env::current_account_id() {
+            return;
+        }
+        let balance = self.internal_snapshot_balance(account_id);
+        let mut checkpoints = self.balance_checkpoints.get(account_id).unwrap_or_default();
+        if checkpoints.record(self.current_snapshot_id, balance) {
+            self.balance_checkpoints.insert(account_id, &checkpoints);
+        }
+    }
+
+    /// Storage of the largest `balance_checkpoints` entry, which `storage_deposit` charges on
+    /// top of the balance.
+    fn measure_checkpoint_storage_usage(&mut self) -> StorageUsage {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = "a".repeat(64);
+        let checkpoints = Checkpoints {
+            last_snapshot_id: u64::MAX,
+            values: vec![(u64::MAX, Balance::MAX); MAX_SNAPSHOT_HISTORY as usize],
+        };
+        self.balance_checkpoints.insert(&tmp_account_id, &checkpoints);
+        let checkpoint_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.balance_checkpoints.remove(&tmp_account_id);
+        checkpoint_storage_usage
+    }
+
+    /// Storage the `balance_checkpoints` entry of `account_id` takes up.
+    fn internal_checkpoint_storage_usage(&mut self, account_id: &AccountId) -> StorageUsage {
+        let checkpoints = match self.balance_checkpoints.get(account_id) {
+            Some(checkpoints) => checkpoints,
+            None => return 0,
+        };
+        let initial_storage_usage = env::storage_usage();
+        self.balance_checkpoints.remove(account_id);
+        let checkpoint_storage_usage = initial_storage_usage - env::storage_usage();
+        self.balance_checkpoints.insert(account_id, &checkpoints);
+        checkpoint_storage_usage
+    }
+
+    /// Must run before any change to the total supply.
+    fn internal_record_total_supply(&mut self, total_supply: Balance) {
+        if self.current_snapshot_id > 0 {
+            self.supply_checkpoints.record(self.current_snapshot_id, total_supply);
+        }
+    }
+
+    fn internal_is_member(&self, account_id: &AccountId) -> bool {
+        if *account_id == env::current_account_id() {
+            return false;
+        }
+        let balance = match self.token.accounts.get(account_id) {
+            Some(balance) => balance,
+            None => return false,
+        };
+        let (staked, unstaking) = self.stakes.get(account_id).map(|stake| (stake.staked, stake.unstaking)).unwrap_or((0, 0));
+        matches!(self.member_min_balance, Some(min) if balance + staked + unstaking >= min)
+            || matches!(self.member_min_stake, Some(min) if staked >= min)
+    }
+
+    /// Adds or removes `account_id` from `members` and logs the change.
+    fn internal_update_membership(&mut self, account_id: &AccountId) {
+        let event = if self.internal_is_member(account_id) {
+            if !self.members.insert(account_id) {
+                return;
+            }
+            "member_added"
+        } else {
+            if !self.members.remove(account_id) {
+                return;
+            }
+            "member_removed"
+        };
+        emit_event(POOL_TOKEN_STANDARD, event, vec![json!({ "account_id": account_id })]);
+    }
+
+    /// Every registration goes through here, so `registered_accounts` stays in sync.
+    fn internal_register_account(&mut self, account_id: &AccountId) {
+        self.token.internal_register_account(account_id);
+        self.registered_accounts += 1;
+    }
+
+    /// Storage of one `members` entry, which `storage_deposit` charges on top of the balance.
+    fn measure_member_storage_usage(&mut self) -> StorageUsage {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = "a".repeat(64);
+        self.members.insert(&tmp_account_id);
+        let member_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.members.remove(&tmp_account_id);
+        member_storage_usage
+    }
+
+    fn internal_allowance(&self, owner_id: &AccountId, spender_id: &AccountId) -> Balance {
+        self.allowances.get(&(owner_id.clone(), spender_id.clone())).unwrap_or(0)
+    }
+
+    fn internal_spend_allowance(&mut self, owner_id: &AccountId, spender_id: &AccountId, amount: Balance) {
+        assert!(amount > 0, "The amount should be a positive number");
+        let key = (owner_id.clone(), spender_id.clone());
+        let allowance = self.allowances.get(&key).unwrap_or(0);
+        assert!(amount <= allowance, "amount exceeds the allowance {} gave {}", owner_id, spender_id);
+        if allowance == amount {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &(allowance - amount));
+        }
+    }
+
+    /// Every token movement between accounts goes through here, so it is logged as an
+    /// `ft_transfer` event.
+    fn internal_transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: Option<String>) {
+        self.internal_checkpoint(sender_id);
+        self.internal_checkpoint(receiver_id);
+        self.token.internal_transfer(sender_id, receiver_id, amount, memo.clone());
+        self.internal_update_membership(sender_id);
+        self.internal_update_membership(receiver_id);
+        emit_event(NEP141_STANDARD, "ft_transfer", vec![json!({
+            "old_owner_id": sender_id,
+            "new_owner_id": receiver_id,
+            "amount": U128(amount),
+            "memo": memo,
+        })]);
+    }
+}
+
+impl Checkpoints {
+    /// Keeps `value` for `snapshot_id` unless that snapshot already has one, and drops the
+    /// checkpoints that only older snapshots than the last `MAX_SNAPSHOT_HISTORY` read.
+    /// Returns whether anything changed.
+    fn record(&mut self, snapshot_id: u64, value: Balance) -> bool {
+        if self.last_snapshot_id >= snapshot_id {
+            return false;
+        }
+        let oldest_kept = snapshot_id.saturating_sub(MAX_SNAPSHOT_HISTORY - 1);
+        self.values.retain(|(id, _)| *id >= oldest_kept);
+        self.values.push((snapshot_id, value));
+        self.last_snapshot_id = snapshot_id;
+        true
+    }
+
+    /// The value at `snapshot_id` is the first one recorded at or after it. `None` means the
+    /// value hasn't changed since and the live one applies.
+    fn value_at(&self, snapshot_id: u64) -> Option<Balance> {
+        if snapshot_id > self.last_snapshot_id {
+            return None;
+        }
+        let (mut low, mut high) = (0, self.values.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.values[mid].0 < snapshot_id {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        self.values.get(low).map(|(_, value)| *value)
+    }
+}
+
+impl Vesting {
+    /// How much of `total` has vested at `timestamp`, rounded down.
+    fn vested(&self, timestamp: u64) -> Balance {
+        if timestamp < self.cliff {
+            0
+        } else if timestamp >= self.end {
+            self.total
+        } else {
+            mul_div(self.total, u128::from(timestamp - self.start), u128::from(self.end - self.start))
+        }
+    }
+}
+
+/// Charges the attached deposit for storage added since `initial_storage_usage` and refunds
+/// the rest, plus the cost of any storage that was freed.
+fn settle_storage_deposit(initial_storage_usage: StorageUsage) {
+    let storage_usage = env::storage_usage();
+    let attached_deposit = env::attached_deposit();
+    let refund = if storage_usage > initial_storage_usage {
+        let required_cost = Balance::from(storage_usage - initial_storage_usage) * env::storage_byte_cost();
+        assert!(
+            attached_deposit >= required_cost,
+            "Must attach {} yoctoNEAR to cover storage",
+            required_cost
+        );
+        attached_deposit - required_cost
+    } else {
+        attached_deposit + Balance::from(initial_storage_usage - storage_usage) * env::storage_byte_cost()
+    };
+    if refund > 1 {
+        Promise::new(env::predecessor_account_id()).transfer(refund);
+    }
+}
+
+impl RewardPerToken {
+    /// Spreads `amount` over `total_staked` tokens.
+    fn add(&mut self, amount: Balance, total_staked: Balance) {
+        assert!(total_staked > 0, "nothing is staked yet");
+        self.whole = self.whole.checked_add(amount / total_staked).expect("reward per token overflows u128");
+        self.frac += mul_div(amount % total_staked, REWARD_PRECISION, total_staked);
+        if self.frac >= REWARD_PRECISION {
+            self.frac -= REWARD_PRECISION;
+            self.whole = self.whole.checked_add(1).expect("reward per token overflows u128");
+        }
+    }
+
+    /// What `staked` tokens earned since the reward per token was `paid`, rounded down.
+    fn earned_since(&self, paid: &RewardPerToken, staked: Balance) -> Balance {
+        let (whole, frac) = if self.frac >= paid.frac {
+            (self.whole - paid.whole, self.frac - paid.frac)
+        } else {
+            (self.whole - paid.whole - 1, self.frac + REWARD_PRECISION - paid.frac)
+        };
+        staked
+            .checked_mul(whole)
+            .and_then(|earned| earned.checked_add(mul_div(staked, frac, REWARD_PRECISION)))
+            .expect("rewards overflow u128")
+    }
+}
+
+/// Computes `a * b / c` rounded down, without overflowing in the intermediate product.
+/// Panics if the result does not fit in u128.
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    assert!(c > 0, "division by zero");
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let mid = (lo_lo >> 64) + (hi_lo & MASK) + (lo_hi & MASK);
+    // The 256 bit product is hi * 2^128 + lo.
+    let lo = (lo_lo & MASK) | (mid << 64);
+    let hi = a_hi * b_hi + (hi_lo >> 64) + (lo_hi >> 64) + (mid >> 64);
+    if hi == 0 {
+        return lo / c;
+    }
+    assert!(hi < c, "result overflows u128");
+    let mut rem = hi;
+    let mut quotient = 0;
+    for i in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> i) & 1);
+        quotient <<= 1;
+        if carry == 1 || rem >= c {
+            rem = rem.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    quotient
+}
+
+/// Logs an `EVENT_JSON` event of `standard` with the given `data` entries. Null fields are
+/// left out, as the standards only have optional fields there.
+fn emit_event(standard: &str, event: &str, mut data: Vec<Value>) {
+    for entry in data.iter_mut() {
+        if let Value::Object(fields) = entry {
+            *fields = std::mem::take(fields).into_iter().filter(|(_, value)| !value.is_null()).collect();
+        }
+    }
+    let event = json!({
+        "standard": standard,
+        "version": "1.0.0",
+        "event": event,
+        "data": data,
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_with_fee(&sender_id, receiver_id.as_ref(), amount.0, memo);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        // The receiver is told about, and can refund, only what it got after the fee.
+        let amount = U128(self.internal_transfer_with_fee(&sender_id, receiver_id.as_ref(), amount.0, memo));
+        ext_ft_receiver::ft_on_transfer(
+            sender_id.clone(),
+            amount,
+            msg,
+            receiver_id.as_ref(),
+            0,
+            env::prepaid_gas() - GAS_FOR_FT_TRANSFER_CALL,
+        )
+        .then(ext_self::ft_resolve_transfer(
+            sender_id,
+            receiver_id.into(),
+            amount,
+            &env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ))
+        .into()
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Logs the refund of unused tokens as a transfer back to the sender, or as a burn if the
+    /// sender's account was closed in the meantime.
+    #[private]
+    fn ft_resolve_transfer(&mut self, sender_id: ValidAccountId, receiver_id: ValidAccountId, amount: U128) -> U128 {
+        let sender_id: AccountId = sender_id.into();
+        let receiver_id_str = receiver_id.to_string();
+        self.internal_checkpoint(&sender_id);
+        self.internal_checkpoint(&receiver_id_str);
+        self.internal_record_total_supply(self.token.total_supply);
+        let (used_amount, burned_amount) = self.token.internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        if used_amount < amount.0 {
+            self.internal_update_membership(&sender_id);
+            self.internal_update_membership(&receiver_id_str);
+        }
+        if burned_amount > 0 {
+            self.on_tokens_burned(receiver_id_str, burned_amount, "refund");
+        } else if used_amount < amount.0 {
+            emit_event(NEP141_STANDARD, "ft_transfer", vec![json!({
+                "old_owner_id": receiver_id_str,
+                "new_owner_id": sender_id,
+                "amount": U128(amount.0 - used_amount),
+                "memo": "refund",
+            })]);
+        }
+        used_amount.into()
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
-near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
+/// Same as `impl_fungible_token_storage!`, but counts registrations in `registered_accounts`.
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(&mut self, account_id: Option<ValidAccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let registered_id: AccountId = account_id.clone().map(|account_id| account_id.into()).unwrap_or_else(env::predecessor_account_id);
+        let was_registered = self.token.accounts.contains_key(&registered_id);
+        let storage_balance = self.token.storage_deposit(account_id, registration_only);
+        if !was_registered {
+            self.registered_accounts += 1;
+        }
+        storage_balance
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.token.storage_withdraw(amount)
+    }
+
+    /// Same as `FungibleToken::internal_storage_unregister`, but checkpoints the balance first
+    /// and refunds the storage deposit minus what the checkpoints of the account still use.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = match self.token.accounts.get(&account_id) {
+            Some(balance) => balance,
+            None => {
+                log!("The account {} is not registered", &account_id);
+                return false;
+            }
+        };
+        assert!(
+            balance == 0 || force.unwrap_or(false),
+            "Can't unregister the account with the positive balance without force"
+        );
+        self.internal_checkpoint(&account_id);
+        let kept = Balance::from(self.internal_checkpoint_storage_usage(&account_id)) * env::storage_byte_cost();
+        self.token.accounts.remove(&account_id);
+        self.token.total_supply -= balance;
+        Promise::new(account_id.clone()).transfer(self.storage_balance_bounds().min.0 + 1 - kept);
+        self.on_account_closed(account_id, balance);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
 
 #[near_bindgen]
 impl FungibleTokenMetadataProvider for Contract {
@@ -117,9 +1290,9 @@ impl FungibleTokenMetadataProvider for Contract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
     use near_sdk::MockedBlockchain;
-    use near_sdk::{testing_env, Balance};
+    use near_sdk::{testing_env, Balance, PromiseResult};
 
     use super::*;
 
@@ -138,12 +1311,21 @@ mod tests {
     fn test_new() {
         let mut context = get_context(accounts(1));
         testing_env!(context.build());
-        let contract = Contract::new_default_meta(accounts(1).into(), TOTAL_SUPPLY.into());
+        let contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
         testing_env!(context.is_view(true).build());
         assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
         assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
     }
 
+    /// The `EVENT_JSON` events named `name` logged so far.
+    fn events(name: &str) -> Vec<Value> {
+        get_logs()
+            .into_iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:").map(|event| near_sdk::serde_json::from_str::<Value>(event).unwrap()))
+            .filter(|event| event["event"] == name)
+            .collect()
+    }
+
     #[test]
     #[should_panic(expected = "The contract is not initialized")]
     fn test_default() {
@@ -156,7 +1338,7 @@ mod tests {
     fn test_transfer() {
         let mut context = get_context(accounts(2));
         testing_env!(context.build());
-        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
         testing_env!(context
             .storage_usage(env::storage_usage())
             .attached_deposit(contract.storage_balance_bounds().min.into())
@@ -182,4 +1364,799 @@ mod tests {
         assert_eq!(contract.ft_balance_of(accounts(2)).0, (TOTAL_SUPPLY - transfer_amount));
         assert_eq!(contract.ft_balance_of(accounts(1)).0, transfer_amount);
     }
+
+    #[test]
+    fn test_transfer_event() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.ft_transfer(accounts(1), 100.into(), Some("rent".to_string()));
+
+        let events = events("ft_transfer");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["standard"], "nep141");
+        assert_eq!(events[0]["version"], "1.0.0");
+        assert_eq!(
+            events[0]["data"],
+            json!([{ "old_owner_id": "charlie", "new_owner_id": "bob", "amount": "100", "memo": "rent" }])
+        );
+    }
+
+    #[test]
+    fn test_transfer_call_refund_event() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.ft_transfer_call(accounts(1), 100.into(), None, "".to_string());
+
+        // The receiver used 70 and hands back the other 30.
+        testing_env!(
+            context.storage_usage(env::storage_usage()).attached_deposit(0).predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(30)).unwrap())]
+        );
+        assert_eq!(contract.ft_resolve_transfer(accounts(2), accounts(1), 100.into()).0, 70);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 70);
+
+        let events = events("ft_transfer");
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0]["data"],
+            json!([{ "old_owner_id": "bob", "new_owner_id": "charlie", "amount": "30", "memo": "refund" }])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "only the factory can upgrade this pool")]
+    fn test_upgrade_only_factory() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.upgrade();
+    }
+
+    #[test]
+    fn test_migrate_keeps_state() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        env::state_write(&contract);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.owner_id, accounts(1).to_string());
+        assert_eq!(contract.factory, accounts(1).to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "the whole supply must be returned")]
+    fn test_close_requires_returned_supply() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.close(accounts(2).to_string(), accounts(1).to_string());
+    }
+
+    #[test]
+    fn test_close_after_holders_unregister() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        assert!(contract.storage_unregister(None));
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).predecessor_account_id(accounts(1)).build());
+        contract.close(accounts(1).to_string(), accounts(1).to_string());
+    }
+
+    #[test]
+    fn test_close_when_owner_is_the_nft_contract() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(1).to_string());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(10_000_000_000_000_000_000_000).build());
+        contract.stake(U128(10));
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.unstake(U128(10));
+        assert!(contract.token.accounts.contains_key(&accounts(0).to_string()));
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).build());
+        contract.close(accounts(1).to_string(), accounts(1).to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "registered accounts have to unregister before the pool can be closed")]
+    fn test_close_with_registered_holders() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).predecessor_account_id(accounts(1)).build());
+        contract.close(accounts(1).to_string(), accounts(1).to_string());
+    }
+
+    #[test]
+    fn test_nft_internal_transfer_within_allowance() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.approve_nft_spending(10.into());
+        assert_eq!(contract.ft_allowance(accounts(1), accounts(3)).0, 10);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(0)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.nft_internal_transfer(accounts(1).to_string(), 4.into());
+        contract.nft_internal_transfer(accounts(1).to_string(), 4.into());
+
+        testing_env!(context.storage_usage(env::storage_usage()).is_view(true).build());
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 8);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 8);
+        assert_eq!(contract.nft_allowance(accounts(1)).0, 2);
+    }
+
+    #[test]
+    fn test_ft_transfer_from() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_approve(accounts(4), 100.into());
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_transfer_from(accounts(1), accounts(2), 60.into(), None);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).is_view(true).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 60);
+        assert_eq!(contract.ft_allowance(accounts(1), accounts(4)).0, 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of at least 1 yoctoNEAR")]
+    fn test_ft_revoke_requires_deposit() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).build());
+        contract.ft_revoke(accounts(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "amount exceeds the allowance")]
+    fn test_ft_transfer_from_after_revoke() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.ft_approve(accounts(4), 100.into());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.ft_revoke(accounts(4));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_transfer_from(accounts(1), accounts(4), 1.into(), None);
+    }
+
+    #[test]
+    fn test_stake_and_unstake() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.stake(100.into());
+        assert_eq!(contract.staked_balance_of(accounts(1)).0, 100);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 100);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.unstake(40.into());
+        assert_eq!(contract.staked_balance_of(accounts(1)).0, 60);
+        assert_eq!(contract.total_staked().0, 60);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "the unstake cooldown has not passed yet")]
+    fn test_withdraw_unstaked_before_cooldown() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.set_unstake_cooldown(U64(1_000));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.stake(100.into());
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).block_timestamp(10).build());
+        contract.unstake(100.into());
+        assert_eq!(contract.get_stake(accounts(1)).unstaking.0, 100);
+        testing_env!(context.storage_usage(env::storage_usage()).block_timestamp(500).build());
+        contract.withdraw_unstaked();
+    }
+
+    #[test]
+    #[should_panic(expected = "amount exceeds the allowance")]
+    fn test_nft_internal_transfer_without_allowance() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.nft_internal_transfer(accounts(1).to_string(), 1.into());
+    }
+
+    #[test]
+    fn test_mul_div_at_u128_limits() {
+        assert_eq!(mul_div(7, 3, 2), 10);
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(mul_div(u128::MAX, REWARD_PRECISION, REWARD_PRECISION), u128::MAX);
+        assert_eq!(mul_div(u128::MAX - 1, u128::MAX, u128::MAX), u128::MAX - 1);
+        assert_eq!(mul_div(u128::MAX, 2, 3), u128::MAX / 3 * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "result overflows u128")]
+    fn test_mul_div_overflow() {
+        mul_div(u128::MAX, 2, 1);
+    }
+
+    #[test]
+    fn test_rewards_pro_rata_round_down() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_transfer(accounts(2), 2.into(), None);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(10_000_000_000_000_000_000_000).build());
+        contract.stake(1.into());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.stake(2.into());
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(100)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.deposit_near_rewards();
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.deposit_ft_rewards(100.into());
+
+        let first = contract.pending_rewards(accounts(1));
+        let second = contract.pending_rewards(accounts(2));
+        assert_eq!((first.near.0, second.near.0), (33, 66));
+        assert_eq!((first.ft.0, second.ft.0), (33, 66));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .predecessor_account_id(accounts(2))
+            .build());
+        let claimed = contract.claim_rewards();
+        assert_eq!(claimed.ft.0, 66);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 66);
+        assert_eq!(contract.pending_rewards(accounts(2)).ft.0, 0);
+    }
+
+    #[test]
+    fn test_rewards_at_u128_limit() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), u128::MAX.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.stake(1.into());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1_000_000_000_000_000_000_000_000_000_000).build());
+        contract.deposit_near_rewards();
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.deposit_ft_rewards((u128::MAX - 1).into());
+
+        let rewards = contract.pending_rewards(accounts(1));
+        assert_eq!(rewards.near.0, 1_000_000_000_000_000_000_000_000_000_000);
+        assert_eq!(rewards.ft.0, u128::MAX - 1);
+    }
+
+    #[test]
+    fn test_reward_per_token_carries_fractions() {
+        let mut reward_per_token = RewardPerToken::default();
+        for _ in 0..4 {
+            reward_per_token.add(1, 4);
+        }
+        assert_eq!((reward_per_token.whole, reward_per_token.frac), (1, 0));
+        assert_eq!(reward_per_token.earned_since(&RewardPerToken::default(), 4), 4);
+
+        let paid = reward_per_token;
+        reward_per_token.add(1, u128::MAX);
+        assert_eq!(reward_per_token.earned_since(&paid, u128::MAX), 0);
+        reward_per_token.add(u128::MAX, u128::MAX);
+        assert_eq!(reward_per_token.earned_since(&paid, u128::MAX), u128::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "stakes, unstaked tokens or rewards are still waiting to be withdrawn")]
+    fn test_close_with_unclaimed_rewards() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.stake(10.into());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(100).build());
+        contract.deposit_near_rewards();
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.unstake(10.into());
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).build());
+        contract.close(accounts(1).into(), accounts(1).into());
+    }
+
+    #[test]
+    fn test_mint_and_burn() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.set_max_supply(Some((TOTAL_SUPPLY + 10).into()));
+        contract.add_minter(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_mint(accounts(1), 10.into(), None);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 10);
+
+        testing_env!(context.attached_deposit(1).predecessor_account_id(accounts(1)).build());
+        contract.ft_burn(4.into(), None);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 6);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY + 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "minting would exceed the max supply")]
+    fn test_mint_beyond_max_supply() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.set_max_supply(Some(TOTAL_SUPPLY.into()));
+        contract.ft_mint(accounts(1), 1.into(), None);
+    }
+
+    #[test]
+    fn test_membership_follows_balance_and_stake() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.set_membership_requirement(Some(10.into()), Some(5.into()));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_transfer(accounts(2), 6.into(), None);
+        assert!(!contract.is_member(accounts(2)));
+        assert_eq!(contract.member_count().0, 1);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.stake(5.into());
+        assert!(contract.is_member(accounts(2)));
+        assert_eq!(contract.members(None, None), vec![accounts(1).to_string(), accounts(2).to_string()]);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.unstake(1.into());
+        assert!(!contract.is_member(accounts(2)));
+        assert_eq!(contract.members(Some(U64(0)), Some(5)), vec![accounts(1).to_string()]);
+    }
+
+    #[test]
+    fn test_staking_everything_keeps_membership() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.set_unstake_cooldown(U64(100));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_transfer(accounts(2), 6.into(), None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.stake(6.into());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+        assert!(contract.is_member(accounts(2)));
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.unstake(6.into());
+        assert!(contract.is_member(accounts(2)));
+
+        testing_env!(context.storage_usage(env::storage_usage()).block_timestamp(100).build());
+        contract.withdraw_unstaked();
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 6);
+        assert!(contract.is_member(accounts(2)));
+        assert_eq!(contract.member_count().0, 2);
+    }
+
+    #[test]
+    fn test_storage_deposit_covers_membership() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        let initial_storage_usage = env::storage_usage();
+        testing_env!(context
+            .storage_usage(initial_storage_usage)
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_transfer(accounts(2), 6.into(), None);
+        assert!(contract.is_member(accounts(2)));
+
+        let used = Balance::from(env::storage_usage() - initial_storage_usage) * env::storage_byte_cost();
+        assert!(used <= contract.storage_balance_bounds().min.0);
+    }
+
+    #[test]
+    fn test_vesting_cliff_then_linear() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .build());
+        contract.add_vesting(accounts(1), VestingSchedule { amount: 1_000.into(), start: U64(0), cliff: U64(100), end: U64(1_000) });
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 1_000);
+
+        testing_env!(context.storage_usage(env::storage_usage()).block_timestamp(50).build());
+        let vesting = contract.get_vesting(accounts(1)).unwrap();
+        assert_eq!((vesting.vested.0, vesting.unvested.0, vesting.claimable.0), (0, 1_000, 0));
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).block_timestamp(500).build());
+        assert_eq!(contract.claim_vested().0, 500);
+        let vesting = contract.get_vesting(accounts(1)).unwrap();
+        assert_eq!((vesting.vested.0, vesting.claimed.0, vesting.claimable.0), (500, 500, 0));
+
+        testing_env!(context.storage_usage(env::storage_usage()).block_timestamp(2_000).build());
+        assert_eq!(contract.claim_vested().0, 500);
+        assert!(contract.get_vesting(accounts(1)).is_none());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    #[should_panic(expected = "nothing to claim yet")]
+    fn test_claim_vested_before_cliff() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let vesting = VestingSchedule { amount: 1_000.into(), start: U64(0), cliff: U64(100), end: U64(1_000) };
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "pool".to_string(),
+            symbol: "POOL".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 0,
+        };
+        let mut contract = Contract::new(accounts(1), TOTAL_SUPPLY.into(), metadata, accounts(3).to_string(), Some(vesting));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 1_000);
+        testing_env!(context.attached_deposit(1).block_timestamp(99).build());
+        contract.claim_vested();
+    }
+
+    #[test]
+    fn test_transfer_fee() {
+        // The factory is fee exempt, so initialize from another account than the senders.
+        let mut context = get_context(accounts(5));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        for account_id in [accounts(0), accounts(2), accounts(4)] {
+            testing_env!(context
+                .storage_usage(env::storage_usage())
+                .attached_deposit(contract.storage_balance_bounds().min.into())
+                .predecessor_account_id(account_id)
+                .build());
+            contract.storage_deposit(None, None);
+        }
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.set_transfer_fee(250);
+        // The owner is the fee recipient, so its own transfers are free.
+        contract.ft_transfer(accounts(2), 3_000.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 3_000);
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(2)).build());
+        contract.ft_transfer(accounts(4), 1_000.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, 975);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY - 3_000 + 25);
+
+        // 2.5% of 999 rounds down to 24.
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        contract.set_fee_recipient(accounts(0));
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(2)).build());
+        contract.ft_transfer(accounts(4), 999.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, 975 + 975);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 24);
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(10_000_000_000_000_000_000_000).build());
+        contract.ft_approve(accounts(4), 100.into());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_transfer_from(accounts(2), accounts(4), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, 975 + 975 + 98);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 24 + 2);
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        contract.add_fee_exempt(accounts(2));
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(2)).build());
+        contract.ft_transfer(accounts(4), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, 975 + 975 + 98 + 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "the transfer fee can be at most")]
+    fn test_transfer_fee_cap() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.set_transfer_fee(MAX_TRANSFER_FEE_BPS + 1);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        let first = contract.snapshot();
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        contract.ft_transfer(accounts(2), 50.into(), None);
+        let second = contract.snapshot();
+        let third = contract.snapshot();
+        contract.ft_mint(accounts(2), 10.into(), None);
+
+        assert_eq!(contract.balance_of_at(accounts(2), first).0, 0);
+        assert_eq!(contract.balance_of_at(accounts(2), second).0, 150);
+        assert_eq!(contract.balance_of_at(accounts(2), third).0, 150);
+        assert_eq!(contract.balance_of_at(accounts(1), first).0, TOTAL_SUPPLY);
+        assert_eq!(contract.balance_of_at(accounts(1), third).0, TOTAL_SUPPLY - 150);
+        assert_eq!(contract.total_supply_at(second).0, TOTAL_SUPPLY);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 10);
+
+        let fourth = contract.snapshot();
+        assert_eq!(contract.balance_of_at(accounts(2), fourth).0, 160);
+        assert_eq!(contract.total_supply_at(fourth).0, TOTAL_SUPPLY + 10);
+    }
+
+    #[test]
+    fn test_snapshots_count_stake_and_vesting() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.add_vesting(accounts(2), VestingSchedule { amount: 1_000.into(), start: U64(0), cliff: U64(0), end: U64(1_000) });
+        contract.stake(100.into());
+        let first = contract.snapshot();
+
+        contract.set_unstake_cooldown(U64(10));
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).block_timestamp(500).build());
+        contract.unstake(40.into());
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(2)).build());
+        contract.claim_vested();
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 500);
+
+        assert_eq!(contract.balance_of_at(accounts(1), first).0, TOTAL_SUPPLY - 1_000);
+        assert_eq!(contract.balance_of_at(accounts(2), first).0, 1_000);
+        assert_eq!(contract.balance_of_at(accounts(0), first).0, 0);
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        let second = contract.snapshot();
+        assert_eq!(contract.balance_of_at(accounts(1), second).0, TOTAL_SUPPLY - 1_000);
+        assert_eq!(contract.balance_of_at(accounts(2), second).0, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "snapshot 1 is too old")]
+    fn test_snapshot_history_is_bounded() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        for _ in 0..MAX_SNAPSHOT_HISTORY + 5 {
+            contract.snapshot();
+            contract.ft_transfer(accounts(2), 1.into(), None);
+        }
+        let checkpoints = contract.balance_checkpoints.get(&accounts(2).to_string()).unwrap();
+        assert_eq!(checkpoints.values.len() as u64, MAX_SNAPSHOT_HISTORY);
+        let oldest = U64(contract.get_current_snapshot_id().0 - MAX_SNAPSHOT_HISTORY + 1);
+        assert_eq!(contract.balance_of_at(accounts(2), oldest).0, 5);
+        contract.balance_of_at(accounts(2), U64(1));
+    }
+
+    #[test]
+    fn test_snapshots_outlive_unregister() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_transfer(accounts(2), 100.into(), None);
+        let first = contract.snapshot();
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(2)).build());
+        contract.ft_transfer(accounts(1), 40.into(), None);
+        let kept = Balance::from(contract.internal_checkpoint_storage_usage(&accounts(2).to_string())) * env::storage_byte_cost();
+        assert!(kept > 0);
+        assert!(contract.storage_unregister(Some(true)));
+        let refund = contract.storage_balance_bounds().min.0 + 1 - kept;
+        let receipts = near_sdk::serde_json::to_string(&near_sdk::test_utils::get_created_receipts()).unwrap();
+        assert!(receipts.contains(&format!("{{\"Transfer\":{{\"deposit\":{}}}}}", refund)));
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        assert_eq!(contract.balance_of_at(accounts(2), first).0, 100);
+        assert_eq!(
+            contract.balance_of_at(accounts(1), first).0 + contract.balance_of_at(accounts(2), first).0,
+            contract.total_supply_at(first).0
+        );
+        let second = contract.snapshot();
+        assert_eq!(contract.balance_of_at(accounts(2), second).0, 0);
+        assert_eq!(contract.total_supply_at(second).0, TOTAL_SUPPLY - 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "snapshot 1 does not exist")]
+    fn test_balance_of_at_unknown_snapshot() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(1).into(), "pool".to_string(), TOTAL_SUPPLY.into(), accounts(3).to_string());
+        contract.balance_of_at(accounts(1), U64(1));
+    }
 }
\ No newline at end of file
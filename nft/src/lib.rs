@@ -1,24 +1,29 @@
 use std::borrow::{Borrow, BorrowMut};
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::ops::Sub;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
 use near_contract_standards::non_fungible_token::metadata::{
     NFTContractMetadata, NonFungibleTokenMetadataProvider, TokenMetadata, NFT_METADATA_SPEC,
 };
 use near_contract_standards::non_fungible_token::{Token, TokenId};
 use near_contract_standards::non_fungible_token::NonFungibleToken;
+use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+use near_contract_standards::non_fungible_token::core::{NonFungibleTokenCore, NonFungibleTokenResolver};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LazyOption, LookupMap, UnorderedSet};
-use near_sdk::json_types::{Base64VecU8, ValidAccountId};
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base58PublicKey, Base64VecU8, ValidAccountId, U64};
+use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{env, ext_contract, near_bindgen, AccountId, Balance, Gas, BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue, log, PromiseResult, CryptoHash};
 use near_sdk::env::{log, promise_result, sha256, state_read};
 
-use near_sdk::serde_json::{json, json_internal_vec};
+use near_sdk::serde_json::{json, json_internal_vec, Value};
 
 near_sdk::setup_alloc!();
 
 #[ext_contract(ext_pool)]
 pub trait DeployPool {
-    fn new_pool(&mut self, poolname :AccountId, owner_id:AccountId,roomsize :U128) -> PromiseOrValue<AccountId>;
+    fn new_pool(&mut self, poolname :AccountId, owner_id:AccountId,roomsize :U128, metadata: FungibleTokenMetadata, code_version: Option<u32>, owner_vesting: Option<VestingSchedule>) -> PromiseOrValue<Option<AccountId>>;
 }
 
 #[ext_contract(ext_ft)]
@@ -30,12 +35,33 @@ pub trait FungibleToken {
 #[ext_contract(ext_self)]
 pub trait MyContract {
     fn nft_mint_callback(&mut self, check:String, caller : AccountId,reciever_id :ValidAccountId,ipfs_hash: String) -> String;
+    fn on_invite_accepted(&mut self, offer_id: U64, offer: InviteOffer) -> Option<TokenId>;
+    fn on_invite_key_added(&mut self, public_key: Base58PublicKey, invite: InviteKey) -> bool;
+    fn on_account_created_and_claimed(&mut self, new_account_id: AccountId, public_key: Base58PublicKey, invite: InviteKey) -> Option<TokenId>;
+    fn on_create_pool(&mut self, owner_id: AccountId, deposit: U128) -> Option<AccountId>;
+}
+
+#[ext_contract(ext_linkdrop)]
+pub trait Linkdrop {
+    fn create_account(&mut self, new_account_id: AccountId, new_public_key: Base58PublicKey) -> Promise;
 }
 
 const NO_DEPOSIT: Balance = 0;
 const BASE_GAS: Gas = 5_000_000_000_000;
 const PROMISE_CALL: Gas = 5_000_000_000_000;
 const GAS_FOR_NFT_ON_APPROVE: Gas = BASE_GAS + PROMISE_CALL;
+const MAX_BATCH_MINT: usize = 50;
+const OWNER_NFT_INVITES: u128 = 2;
+const OWNER_CLASS: &str = "owner";
+const INVITE_CLASS: &str = "invite";
+const DEFAULT_INVITE_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+/// Gas allowance of an invite key, paid out of the deposit of `invite_with_key`.
+const ACCESS_KEY_ALLOWANCE: Balance = 250_000_000_000_000_000_000_000;
+const INVITE_KEY_METHODS: &[u8] = b"claim_invite,create_account_and_claim_invite";
+const LINKDROP_ACCOUNT: &str = "testnet";
+const ON_CREATE_ACCOUNT_CALLBACK_GAS: Gas = 20_000_000_000_000;
+const ON_INVITE_KEY_ADDED_CALLBACK_GAS: Gas = 10_000_000_000_000;
+const ON_CREATE_POOL_CALLBACK_GAS: Gas = 20_000_000_000_000;
 
 
 #[near_bindgen]
@@ -46,7 +72,76 @@ pub struct Contract {
     tokenIds : LazyOption<String>,
     ContractGlobal : LazyOption<AccountId>,
     OwnerNftStore : LookupMap<AccountId,String>,
-    InviteNftCounts : LookupMap<AccountId,u128>
+    InviteNftCounts : LookupMap<AccountId,u128>,
+    Minters : UnorderedSet<AccountId>,
+    /// Per-token soulbound flags set with `set_soulbound`; they win over the class flag.
+    SoulboundTokens : LookupMap<TokenId, bool>,
+    Classes : UnorderedMap<String, TokenClass>,
+    TokenClasses : LookupMap<TokenId, String>,
+    InviteOffers : UnorderedMap<u64, InviteOffer>,
+    NextInviteOfferId : u64,
+    InviteTtl : u64,
+    InviteKeys : LookupMap<Vec<u8>, InviteKey>,
+    PoolEscrows : LookupMap<AccountId, PoolEscrow>,
+    /// Owner of each escrowed token, i.e. the key of its `PoolEscrows` entry.
+    EscrowOwners : LookupMap<TokenId, AccountId>,
+    /// The only pool factory `create_pool` calls, set with `set_pool_factory`.
+    PoolFactory : LazyOption<AccountId>
+}
+
+/// Vesting of the creator's pool tokens, which the pool factory passes on to the new pool.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingSchedule {
+    pub amount: U128,
+    pub start: U64,
+    pub cliff: U64,
+    pub end: U64,
+}
+
+/// Owner NFT held by this contract while `owner` has a pool with `factory`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolEscrow {
+    pub token_id: TokenId,
+    pub factory: AccountId,
+}
+
+/// An invite registered with `invite_with_key`. Whatever `deposit` is left above
+/// `ACCESS_KEY_ALLOWANCE` goes to the account that claims the invite.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InviteKey {
+    pub inviter: AccountId,
+    pub deposit: U128,
+}
+
+/// A pending invite from `inviter` that `invitee` can accept until `expires_at` (nanoseconds).
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InviteOffer {
+    pub inviter: AccountId,
+    pub invitee: AccountId,
+    pub expires_at: U64,
+}
+
+/// A class of tokens, e.g. `owner` or `invite`. A token is soulbound if its class is, unless
+/// `set_soulbound` gave the token its own flag.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenClass {
+    pub soulbound: bool,
+    pub supply: U64,
+}
+
+/// `Token` as returned by `nft_token`, plus the soulbound flag of this contract.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenView {
+    #[serde(flatten)]
+    pub token: Token,
+    pub soulbound: bool,
+    pub class: Option<String>,
 }
 
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 288 288'%3E%3Cg id='l' data-name='l'%3E%3Cpath d='M187.58,79.81l-30.1,44.69a3.2,3.2,0,0,0,4.75,4.2L191.86,103a1.2,1.2,0,0,1,2,.91v80.46a1.2,1.2,0,0,1-2.12.77L102.18,77.93A15.35,15.35,0,0,0,90.47,72.5H87.34A15.34,15.34,0,0,0,72,87.84V201.16A15.34,15.34,0,0,0,87.34,216.5h0a15.35,15.35,0,0,0,13.08-7.31l30.1-44.69a3.2,3.2,0,0,0-4.75-4.2L96.14,186a1.2,1.2,0,0,1-2-.91V104.61a1.2,1.2,0,0,1,2.12-.77l89.55,107.23a15.35,15.35,0,0,0,11.71,5.43h3.13A15.34,15.34,0,0,0,216,201.16V87.84A15.34,15.34,0,0,0,200.66,72.5h0A15.35,15.35,0,0,0,187.58,79.81Z'/%3E%3C/g%3E%3C/svg%3E";
@@ -62,6 +157,15 @@ enum StorageKey {
     ContractOwner,
     OwnerNft,
     InviteCount,
+    Minters,
+    Soulbound,
+    Classes,
+    TokenClasses,
+    InviteOffers,
+    InviteKeys,
+    PoolEscrows,
+    EscrowOwners,
+    PoolFactory,
     TokensPerOwner { account_hash: Vec<u8> },
     TokenPerOwnerInner { account_id_hash: CryptoHash },
 }
@@ -83,7 +187,7 @@ impl Contract {
             reference_hash: None
         };
         let initcounter: String = "0".to_string();
-        Self {
+        let mut this = Self {
             tokens: NonFungibleToken::new(
                 StorageKey::NonFungibleToken,
                 owner_id.clone(),
@@ -95,8 +199,22 @@ impl Contract {
             tokenIds: LazyOption::new(StorageKey::TokenIds, Some(&initcounter)),
             ContractGlobal: LazyOption::new(StorageKey::ContractOwner, Some(&owner_id.into())),
             OwnerNftStore: LookupMap::new(StorageKey::OwnerNft),
-            InviteNftCounts : LookupMap::new( StorageKey::InviteCount)
-        }
+            InviteNftCounts : LookupMap::new( StorageKey::InviteCount),
+            Minters : UnorderedSet::new(StorageKey::Minters),
+            SoulboundTokens : LookupMap::new(StorageKey::Soulbound),
+            Classes : UnorderedMap::new(StorageKey::Classes),
+            TokenClasses : LookupMap::new(StorageKey::TokenClasses),
+            InviteOffers : UnorderedMap::new(StorageKey::InviteOffers),
+            NextInviteOfferId : 0,
+            InviteTtl : DEFAULT_INVITE_TTL,
+            InviteKeys : LookupMap::new(StorageKey::InviteKeys),
+            PoolEscrows : LookupMap::new(StorageKey::PoolEscrows),
+            EscrowOwners : LookupMap::new(StorageKey::EscrowOwners),
+            PoolFactory : LazyOption::new(StorageKey::PoolFactory, None)
+        };
+        this.Classes.insert(&OWNER_CLASS.to_string(), &TokenClass { soulbound: true, supply: U64(0) });
+        this.Classes.insert(&INVITE_CLASS.to_string(), &TokenClass { soulbound: false, supply: U64(0) });
+        this
     }
 
     /// Mint a new token with ID=`token_id` belonging to `receiver_id`.
@@ -133,23 +251,105 @@ impl Contract {
         ))
     }
 
+    /// Escrows the caller's owner NFT and asks the pool factory `pool_id` to create their pool.
+    /// `pool_id` must be the factory set with `set_pool_factory`. If the factory rejects the
+    /// call, `on_create_pool` gives the NFT and the attached deposit back. With `owner_vesting`,
+    /// that part of the caller's pool tokens vests instead of being liquid right away.
     #[payable]
-    pub fn create_pool(&mut self, pool_id: AccountId, roomsize: U128) -> PromiseOrValue<String> {
+    pub fn create_pool(&mut self, pool_id: AccountId, roomsize: U128, metadata: FungibleTokenMetadata, owner_vesting: Option<VestingSchedule>) -> Promise {
+        metadata.assert_valid();
+        assert_eq!(Some(pool_id.clone()), self.PoolFactory.get(), "{} is not the pool factory", pool_id);
         let account_id = env::predecessor_account_id();
         let tokenid = self.OwnerNftStore.get(&account_id.clone()).unwrap_or_else(|| "".to_string());
         log!(tokenid);
         if tokenid == "" {
             assert!(false, "token id not found")
         }
+        assert_eq!(self.TokenClasses.get(&tokenid).as_deref(), Some(OWNER_CLASS), "only owner nfts can create pools");
+        assert!(self.PoolEscrows.get(&account_id).is_none(), "owner nft is already escrowed for a pool");
 
         let validCurrentId = ValidAccountId::try_from(env::current_account_id()).unwrap();
         self.tokens.internal_transfer(&env::predecessor_account_id(), &env::current_account_id(), &tokenid.clone(), None, None);
+        self.PoolEscrows.insert(&account_id, &PoolEscrow { token_id: tokenid.clone(), factory: pool_id.clone() });
+        self.EscrowOwners.insert(&tokenid, &account_id);
         let mut poolname: Vec<&str> = account_id.split(".").collect();
         let counter = self.tokenIds.get().unwrap().to_owned();
         let mut finalname = poolname[0].to_string();
         finalname.push_str("creatorsroomandpools");
 
-        ext_pool::new_pool(finalname.to_string(), env::predecessor_account_id(), roomsize, &pool_id, NO_DEPOSIT, env::prepaid_gas() / 2).into()
+        // The attached deposit pays for the pool subaccount created by the factory.
+        ext_pool::new_pool(finalname.to_string(), env::predecessor_account_id(), roomsize, metadata, None, owner_vesting, &pool_id, env::attached_deposit(), env::prepaid_gas() / 2)
+            .then(ext_self::on_create_pool(
+                account_id,
+                U128(env::attached_deposit()),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                ON_CREATE_POOL_CALLBACK_GAS,
+            ))
+    }
+
+    /// Handles the factory's answer to `create_pool`. If the factory call failed, the escrowed
+    /// NFT and the deposit go back to `owner_id`. Failures after the factory accepted the call
+    /// are refunded by the factory itself, which then calls `release_escrowed_nft`.
+    #[private]
+    pub fn on_create_pool(&mut self, owner_id: AccountId, deposit: U128) -> Option<AccountId> {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(result) => near_sdk::serde_json::from_slice::<Option<AccountId>>(&result).unwrap_or(None),
+            PromiseResult::Failed => {
+                log!("pool factory rejected create_pool, refunding {}", owner_id);
+                if let Some(escrow) = self.PoolEscrows.get(&owner_id) {
+                    self.PoolEscrows.remove(&owner_id);
+                    self.EscrowOwners.remove(&escrow.token_id);
+                    self.tokens.internal_transfer(
+                        &env::current_account_id(),
+                        &owner_id,
+                        &escrow.token_id,
+                        None,
+                        Some("pool escrow released".to_string()),
+                    );
+                }
+                if deposit.0 > 0 {
+                    Promise::new(owner_id).transfer(deposit.0);
+                }
+                None
+            }
+        }
+    }
+
+    /// Sets the pool factory `create_pool` may call.
+    pub fn set_pool_factory(&mut self, pool_factory: ValidAccountId) {
+        self.assert_owner();
+        self.PoolFactory.set(pool_factory.as_ref());
+    }
+
+    pub fn get_pool_factory(&self) -> Option<AccountId> {
+        self.PoolFactory.get()
+    }
+
+    /// Gives the owner NFT escrowed by `create_pool` back to `owner_id`. Only the pool factory
+    /// the NFT was escrowed for can call this, e.g. when creating the pool failed.
+    pub fn release_escrowed_nft(&mut self, owner_id: AccountId) {
+        let escrow = self.PoolEscrows.get(&owner_id).expect("no escrowed nft for this account");
+        assert_eq!(env::predecessor_account_id(), escrow.factory, "only the pool factory can release this nft");
+        self.PoolEscrows.remove(&owner_id);
+        self.EscrowOwners.remove(&escrow.token_id);
+        self.tokens.internal_transfer(
+            &env::current_account_id(),
+            &owner_id,
+            &escrow.token_id,
+            None,
+            Some("pool escrow released".to_string()),
+        );
+    }
+
+    pub fn get_pool_escrow(&self, owner_id: AccountId) -> Option<PoolEscrow> {
+        self.PoolEscrows.get(&owner_id)
     }
 
     #[payable]
@@ -192,7 +392,7 @@ impl Contract {
                     }
                 },
             }
-            let invites:u128= 2;
+            let invites:u128= OWNER_NFT_INVITES;
             self.InviteNftCounts.insert(&reciever_id.clone().into(), &invites);
             let latest_counter: String = self.tokenIds.get().unwrap();
             let int_counter: i32 = latest_counter.parse().unwrap();
@@ -215,38 +415,6 @@ impl Contract {
                 media_hash: Some(Base64VecU8::from(media_hash.clone()))
             };
 
-        }else if check.eq("checktransfer") {
-            match env::promise_result(0) {
-                PromiseResult::NotReady => unreachable!(),
-                PromiseResult::Failed => unreachable!(),
-                PromiseResult::Successful(result) => {}
-            }
-            let data= self.InviteNftCounts.get(&caller.clone().into()).unwrap();
-
-            let invites:u128= data-1;
-            self.InviteNftCounts.insert(&caller.clone().into(), &invites);
-
-            log!("invite init started");
-            log!("amount transfer init");
-            let media_hash = env::sha256(ipfs_hash.clone().as_bytes());
-
-            // log!(int_counter.to_string());
-            let newhash=format!("https://cattery-api.amanraj.dev/api/img/{}",(int_counter+1));
-            let media_hash = env::sha256(newhash.clone().as_bytes());
-            owner_metadata = TokenMetadata {
-                title: Some("invite nft".to_string()),
-                description: Some(format!("invite nft for {}", reciever_id.to_string())),
-                copies: Some(1),
-                issued_at: None,
-                expires_at: None,
-                starts_at: Some(env::block_timestamp().to_string()),
-                updated_at: Some(env::block_timestamp().to_string()),
-                extra: None,
-                reference: None,
-                reference_hash: None,
-                media: Some(newhash.clone()),
-                media_hash: Some(Base64VecU8::from(media_hash.clone()))
-            };
         }
         log!("{}",ipfs_hash.clone());
 
@@ -254,57 +422,577 @@ impl Contract {
         self.tokenIds.replace(&(int_counter + 1).to_string());
         self.OwnerNftStore.insert(&reciever_id.clone().into(), &(int_counter + 1).to_string());
 
-        if check.eq("checktransfer"){
-            let invitecaller : ValidAccountId= ValidAccountId::try_from(caller.clone()).unwrap();
-            self.internal_mint((int_counter+1).to_string(),invitecaller , Some(owner_metadata));
-            self.tokens.internal_transfer(&caller, &reciever_id.clone().into(), &(int_counter+1).to_string(),None,None);
-            return (int_counter+1).to_string();
-
-        }
-
-        self.internal_mint((int_counter + 1).to_string(), reciever_id.clone(), Some(owner_metadata));
+        self.internal_mint((int_counter + 1).to_string(), reciever_id.clone(), Some(owner_metadata), OWNER_CLASS);
         return (int_counter+1).to_string();
     }
 
+    /// Offers an invite NFT to `invitee` and returns the offer id.
+    ///
+    /// One invite is taken from the caller's quota right away. Nothing is minted or paid until
+    /// the invitee calls `accept_invite`. A declined or expired offer gives the invite back.
     #[payable]
-    pub fn invite_other( &mut self , invitee : ValidAccountId)->Promise{
-        let inviteeleft=self.InviteNftCounts.get(&env::predecessor_account_id()).unwrap_or_else(||10);
+    pub fn invite_other( &mut self , invitee : ValidAccountId)->U64{
         let caller = env::predecessor_account_id();
-        if inviteeleft==10{
-            assert!(false,"no invitee nft are left")
-        }
-        log!("invite init started");
-        log!("amount transfer init");
-        let amounttransfer = U128::try_from(0).unwrap();
+        let inviteeleft=self.InviteNftCounts.get(&caller).unwrap_or(0);
+        assert!(inviteeleft > 0, "no invitee nft are left");
+        assert_ne!(caller, invitee.to_string(), "cannot invite yourself");
+        self.InviteNftCounts.insert(&caller, &(inviteeleft - 1));
+
+        let offer_id = self.NextInviteOfferId;
+        self.NextInviteOfferId += 1;
+        let offer = InviteOffer {
+            inviter: caller,
+            invitee: invitee.into(),
+            expires_at: U64(env::block_timestamp() + self.InviteTtl),
+        };
+        log!("invite offer {} from {} to {}", offer_id, offer.inviter, offer.invitee);
+        self.InviteOffers.insert(&offer_id, &offer);
+        U64(offer_id)
+    }
+
+    /// Accepts a pending invite offer. The invitee pays the invite fee in the platform FT and
+    /// receives the invite NFT in `on_invite_accepted`. The fee is moved with
+    /// `nft_internal_transfer`, so the invitee must first allow it with `approve_nft_spending`
+    /// on the platform FT.
+    #[payable]
+    pub fn accept_invite(&mut self, offer_id: U64) -> Promise {
+        let offer = self.InviteOffers.get(&offer_id.0).expect("invite offer not found");
+        assert_eq!(env::predecessor_account_id(), offer.invitee, "only the invitee can accept this offer");
+        assert!(env::block_timestamp() < offer.expires_at.0, "invite offer has expired");
+        // Taken out while the payment is in flight so the offer cannot be accepted twice.
+        self.InviteOffers.remove(&offer_id.0);
 
         let first = Promise::new("nfterc20contract.somenewname.testnet".to_string()).function_call(
             b"nft_internal_transfer".to_vec(),
-            json!({"invitee":invitee.to_string(),"amount":U128::from(1)}).to_string().into_bytes(),
+            json!({"invitee":offer.invitee,"amount":U128::from(1)}).to_string().into_bytes(),
             0,
             5_000_000_000_000
         );
-
-        log!("after transfer");
-        let second= ext_self::nft_mint_callback(
-            "checktransfer".to_string(),
-            env::predecessor_account_id().into(),
-            invitee,
-            "".to_string(),
+        let second = ext_self::on_invite_accepted(
+            offer_id,
+            offer,
             &env::current_account_id(), // contract account id
             9630000000000000000000, // yocto NEAR to attach
             env::prepaid_gas()/2 // gas to attach
-            );
+        );
 
         first.then(second)
     }
 
+    #[private]
+    #[payable]
+    pub fn on_invite_accepted(&mut self, offer_id: U64, offer: InviteOffer) -> Option<TokenId> {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Failed => {
+                log!("invite payment failed, offer {} is pending again", offer_id.0);
+                self.InviteOffers.insert(&offer_id.0, &offer);
+                None
+            }
+            PromiseResult::Successful(_) => {
+                let invitee = ValidAccountId::try_from(offer.invitee.clone()).unwrap();
+                Some(self.mint_invite_nft(invitee))
+            }
+        }
+    }
+
+    /// Declines a pending invite offer and gives the invite back to the inviter.
+    pub fn decline_invite(&mut self, offer_id: U64) {
+        let offer = self.InviteOffers.get(&offer_id.0).expect("invite offer not found");
+        assert_eq!(env::predecessor_account_id(), offer.invitee, "only the invitee can decline this offer");
+        self.InviteOffers.remove(&offer_id.0);
+        self.return_invite(&offer.inviter);
+    }
+
+    /// Removes an expired invite offer and gives the invite back to the inviter.
+    pub fn reclaim_invite(&mut self, offer_id: U64) {
+        let offer = self.InviteOffers.get(&offer_id.0).expect("invite offer not found");
+        assert!(env::block_timestamp() >= offer.expires_at.0, "invite offer has not expired yet");
+        self.InviteOffers.remove(&offer_id.0);
+        self.return_invite(&offer.inviter);
+    }
+
+    /// Registers a one-time invite key, like NEAR's linkdrop contract.
+    ///
+    /// A function-call access key for `public_key` is added to this contract, limited to
+    /// `claim_invite` and `create_account_and_claim_invite`. Whoever holds the secret key can
+    /// claim the invite NFT to any account. One invite is taken from the caller's quota.
+    ///
+    /// The invite is only registered in `on_invite_key_added` once the key was added, so a key
+    /// this contract already has, e.g. one of its own full access keys, is never registered
+    /// and can't be deleted with `cancel_invite_key` or a claim.
+    #[payable]
+    pub fn invite_with_key(&mut self, public_key: Base58PublicKey) -> Promise {
+        let caller = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        assert!(deposit >= ACCESS_KEY_ALLOWANCE, "Attach at least {} yoctoNEAR for the invite key", ACCESS_KEY_ALLOWANCE);
+        let inviteeleft = self.InviteNftCounts.get(&caller).unwrap_or(0);
+        assert!(inviteeleft > 0, "no invitee nft are left");
+
+        let pk: Vec<u8> = public_key.into();
+        assert!(self.InviteKeys.get(&pk).is_none(), "invite key already registered");
+        self.InviteNftCounts.insert(&caller, &(inviteeleft - 1));
+
+        Promise::new(env::current_account_id())
+            .add_access_key(
+                pk.clone(),
+                ACCESS_KEY_ALLOWANCE,
+                env::current_account_id(),
+                INVITE_KEY_METHODS.to_vec(),
+            )
+            .then(ext_self::on_invite_key_added(
+                Base58PublicKey(pk),
+                InviteKey { inviter: caller, deposit: U128(deposit) },
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                ON_INVITE_KEY_ADDED_CALLBACK_GAS,
+            ))
+    }
+
+    /// Registers the invite once its key was added. If adding the key failed, the inviter gets
+    /// the invite and the whole deposit back.
+    #[private]
+    pub fn on_invite_key_added(&mut self, public_key: Base58PublicKey, invite: InviteKey) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                self.InviteKeys.insert(&public_key.into(), &invite);
+                true
+            }
+            PromiseResult::Failed => {
+                log!("invite key could not be added, refunding {}", invite.inviter);
+                self.return_invite(&invite.inviter);
+                Promise::new(invite.inviter).transfer(invite.deposit.0);
+                false
+            }
+        }
+    }
+
+    /// Claims the invite of the signing key to an existing `account_id`.
+    /// Must be signed with the invite key itself.
+    pub fn claim_invite(&mut self, account_id: ValidAccountId) -> TokenId {
+        let (pk, invite) = self.take_invite_key();
+        Promise::new(env::current_account_id()).delete_key(pk);
+        let welcome_balance = invite.deposit.0 - ACCESS_KEY_ALLOWANCE;
+        if welcome_balance > 0 {
+            Promise::new(account_id.to_string()).transfer(welcome_balance);
+        }
+        self.mint_invite_nft(account_id)
+    }
+
+    /// Creates `new_account_id` through the linkdrop contract and claims the invite of the
+    /// signing key to it. Whatever the inviter deposited above the key allowance funds the
+    /// new account. Must be signed with the invite key itself.
+    ///
+    /// Like in NEAR's linkdrop, the key is only deleted once the account exists, so failed
+    /// attempts are paid from what is left of the key's allowance.
+    pub fn create_account_and_claim_invite(
+        &mut self,
+        new_account_id: ValidAccountId,
+        new_public_key: Base58PublicKey,
+    ) -> Promise {
+        let (pk, invite) = self.take_invite_key();
+        let welcome_balance = invite.deposit.0 - ACCESS_KEY_ALLOWANCE;
+        ext_linkdrop::create_account(
+            new_account_id.to_string(),
+            new_public_key,
+            &LINKDROP_ACCOUNT.to_string(),
+            welcome_balance,
+            env::prepaid_gas() / 3,
+        )
+        .then(ext_self::on_account_created_and_claimed(
+            new_account_id.into(),
+            Base58PublicKey(pk),
+            invite,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            ON_CREATE_ACCOUNT_CALLBACK_GAS,
+        ))
+    }
+
+    /// Deletes the invite key and mints the invite NFT to the new account, or registers the
+    /// invite again if the account could not be created.
+    #[private]
+    pub fn on_account_created_and_claimed(
+        &mut self,
+        new_account_id: AccountId,
+        public_key: Base58PublicKey,
+        invite: InviteKey,
+    ) -> Option<TokenId> {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(result) if near_sdk::serde_json::from_slice::<bool>(&result).unwrap_or(false) => {
+                Promise::new(env::current_account_id()).delete_key(public_key.into());
+                Some(self.mint_invite_nft(ValidAccountId::try_from(new_account_id).unwrap()))
+            }
+            _ => {
+                log!("account creation failed, invite key can be used again");
+                self.InviteKeys.insert(&public_key.into(), &invite);
+                None
+            }
+        }
+    }
+
+    /// Cancels an unclaimed invite key and gives the invite back. Only the deposit above
+    /// `ACCESS_KEY_ALLOWANCE` is refunded; the allowance covers gas the key may have burnt.
+    pub fn cancel_invite_key(&mut self, public_key: Base58PublicKey) -> Promise {
+        let pk: Vec<u8> = public_key.into();
+        let invite = self.InviteKeys.get(&pk).expect("invite key not found");
+        assert_eq!(env::predecessor_account_id(), invite.inviter, "only the inviter can cancel this invite");
+        self.InviteKeys.remove(&pk);
+        self.return_invite(&invite.inviter);
+        let refund = invite.deposit.0 - ACCESS_KEY_ALLOWANCE;
+        let promise = Promise::new(env::current_account_id()).delete_key(pk);
+        if refund > 0 {
+            promise.then(Promise::new(invite.inviter).transfer(refund))
+        } else {
+            promise
+        }
+    }
+
+    pub fn get_invite_key(&self, public_key: Base58PublicKey) -> Option<InviteKey> {
+        self.InviteKeys.get(&public_key.into())
+    }
+
+    /// Sets how long new invite offers stay open, in nanoseconds.
+    pub fn set_invite_ttl(&mut self, ttl: U64) {
+        self.assert_owner();
+        self.InviteTtl = ttl.0;
+    }
+
+    pub fn get_invite_ttl(&self) -> U64 {
+        U64(self.InviteTtl)
+    }
+
+    pub fn get_invite_offer(&self, offer_id: U64) -> Option<InviteOffer> {
+        self.InviteOffers.get(&offer_id.0)
+    }
+
+    pub fn get_invite_offers_for(
+        &self,
+        account_id: ValidAccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<(U64, InviteOffer)> {
+        let start = u128::from(from_index.unwrap_or(U128(0))) as usize;
+        self.InviteOffers
+            .iter()
+            .filter(|(_, offer)| offer.invitee == account_id.to_string())
+            .skip(start)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|(offer_id, offer)| (U64(offer_id), offer))
+            .collect()
+    }
+
+
+    /// Marks `token_id` as soulbound (non-transferable) or transferable, whatever its class
+    /// says. `clear_soulbound` makes it follow its class again. Owner NFTs always stay
+    /// soulbound, as `OwnerNftStore` and pool escrows follow them; they move only with
+    /// `admin_recover_token`.
+    pub fn set_soulbound(&mut self, token_id: TokenId, soulbound: bool) {
+        self.assert_owner();
+        assert!(self.tokens.owner_by_id.get(&token_id).is_some(), "Token not found");
+        if !soulbound {
+            assert_ne!(self.TokenClasses.get(&token_id).as_deref(), Some(OWNER_CLASS), "owner nfts are always soulbound");
+        }
+        self.SoulboundTokens.insert(&token_id, &soulbound);
+    }
+
+    pub fn clear_soulbound(&mut self, token_id: TokenId) {
+        self.assert_owner();
+        self.SoulboundTokens.remove(&token_id);
+    }
+
+    pub fn nft_is_soulbound(&self, token_id: TokenId) -> bool {
+        self.is_soulbound(&token_id)
+    }
+
+    pub fn nft_token_view(&self, token_id: TokenId) -> Option<TokenView> {
+        let soulbound = self.is_soulbound(&token_id);
+        let class = self.TokenClasses.get(&token_id);
+        self.internal_token(token_id).map(|token| TokenView { token, soulbound, class })
+    }
+
+    /// Registers a custom token class next to the built-in `owner` and `invite` classes.
+    pub fn register_class(&mut self, class: String, soulbound: bool) {
+        self.assert_owner();
+        assert!(self.Classes.get(&class).is_none(), "Class {} already exists", class);
+        self.Classes.insert(&class, &TokenClass { soulbound, supply: U64(0) });
+    }
+
+    pub fn set_class_soulbound(&mut self, class: String, soulbound: bool) {
+        self.assert_owner();
+        assert!(soulbound || class != OWNER_CLASS, "owner nfts are always soulbound");
+        let mut token_class = self.Classes.get(&class).expect("Class not found");
+        token_class.soulbound = soulbound;
+        self.Classes.insert(&class, &token_class);
+    }
+
+    pub fn nft_classes(&self) -> Vec<(String, TokenClass)> {
+        self.Classes.to_vec()
+    }
+
+    pub fn nft_class_of(&self, token_id: TokenId) -> Option<String> {
+        self.TokenClasses.get(&token_id)
+    }
+
+    pub fn nft_supply_for_class(&self, class: String) -> U128 {
+        self.Classes.get(&class).map(|token_class| U128(token_class.supply.0 as u128)).unwrap_or(U128(0))
+    }
+
+    pub fn nft_tokens_for_owner_by_class(
+        &self,
+        account_id: ValidAccountId,
+        class: String,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<TokenView> {
+        let tokens_per_owner = self.tokens.tokens_per_owner.as_ref().expect("Could not find tokens_per_owner");
+        let token_set = match tokens_per_owner.get(account_id.as_ref()) {
+            Some(token_set) => token_set,
+            None => return vec![],
+        };
+        let start = u128::from(from_index.unwrap_or(U128(0))) as usize;
+        token_set
+            .iter()
+            .filter(|token_id| self.TokenClasses.get(token_id).as_deref() == Some(class.as_str()))
+            .skip(start)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .filter_map(|token_id| self.nft_token_view(token_id))
+            .collect()
+    }
+
+    /// Whether `account_id` holds at least one token of `class`, e.g. an owner NFT.
+    pub fn nft_holds_class(&self, account_id: ValidAccountId, class: String) -> bool {
+        let tokens_per_owner = self.tokens.tokens_per_owner.as_ref().expect("Could not find tokens_per_owner");
+        tokens_per_owner
+            .get(account_id.as_ref())
+            .map(|token_set| {
+                token_set.iter().any(|token_id| self.TokenClasses.get(&token_id).as_deref() == Some(class.as_str()))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Moves `token_id` to `receiver_id` regardless of the soulbound flag, e.g. to recover an
+    /// owner NFT from a lost account. An escrowed token is taken out of its pool escrow, and an
+    /// owner NFT becomes the owner NFT of `receiver_id`, who must not have one yet.
+    pub fn admin_recover_token(&mut self, token_id: TokenId, receiver_id: ValidAccountId) {
+        self.assert_owner();
+        let holder_id = self.tokens.owner_by_id.get(&token_id).expect("Token not found");
+        let owner_id = match self.EscrowOwners.remove(&token_id) {
+            Some(escrow_owner_id) => {
+                self.PoolEscrows.remove(&escrow_owner_id);
+                escrow_owner_id
+            }
+            None => holder_id.clone(),
+        };
+        if self.OwnerNftStore.get(&owner_id).as_ref() == Some(&token_id) {
+            assert!(self.OwnerNftStore.get(receiver_id.as_ref()).is_none(), "{} already has an owner nft", receiver_id);
+            self.OwnerNftStore.remove(&owner_id);
+            self.OwnerNftStore.insert(receiver_id.as_ref(), &token_id);
+        }
+        self.tokens.internal_transfer(&holder_id, receiver_id.as_ref(), &token_id, None, Some("admin recovery".to_string()));
+    }
+
+    /// Mints a batch of NFTs of `class` (owner NFTs by default) in a single call, e.g. for
+    /// airdrops. Owner NFTs also give their receivers invites; other classes have to be
+    /// registered with `register_class` first.
+    ///
+    /// Only the contract owner or an account added with `add_minter` may call this. Receivers
+    /// of owner NFTs must not have one yet. At most
+    /// `MAX_BATCH_MINT` tokens are minted per call to stay within the gas limit. The attached
+    /// deposit must cover the storage used by the whole batch; the rest is refunded.
+    /// A single `nft_mint` event is emitted for the batch.
+    #[payable]
+    pub fn nft_batch_mint(&mut self, tokens: Vec<(ValidAccountId, TokenMetadata)>, class: Option<String>) -> Vec<TokenId> {
+        self.assert_minter();
+        assert!(!tokens.is_empty(), "nothing to mint");
+        assert!(tokens.len() <= MAX_BATCH_MINT, "at most {} tokens can be minted per batch", MAX_BATCH_MINT);
+
+        let initial_storage_usage = env::storage_usage();
+        let latest_counter: String = self.tokenIds.get().unwrap();
+        let mut int_counter: i32 = latest_counter.parse().unwrap();
+        let mut minted: Vec<TokenId> = Vec::with_capacity(tokens.len());
+        let mut minted_by_owner: Vec<(AccountId, Vec<TokenId>)> = Vec::new();
+
+        for (receiver_id, token_metadata) in tokens {
+            int_counter += 1;
+            let token_id = int_counter.to_string();
+            let owner_id: AccountId = receiver_id.clone().into();
+            match class.as_deref() {
+                None | Some(OWNER_CLASS) => {
+                    self.internal_mint_owner_nft(token_id.clone(), receiver_id, Some(token_metadata));
+                }
+                Some(class) => {
+                    self.internal_mint(token_id.clone(), receiver_id, Some(token_metadata), class);
+                }
+            }
+
+            match minted_by_owner.iter_mut().find(|(owner, _)| *owner == owner_id) {
+                Some((_, token_ids)) => token_ids.push(token_id.clone()),
+                None => minted_by_owner.push((owner_id, vec![token_id.clone()])),
+            }
+            minted.push(token_id);
+        }
+        self.tokenIds.replace(&int_counter.to_string());
+
+        let required_cost = Balance::from(env::storage_usage() - initial_storage_usage) * env::storage_byte_cost();
+        let attached_deposit = env::attached_deposit();
+        assert!(
+            attached_deposit >= required_cost,
+            "Must attach {} yoctoNEAR to cover storage",
+            required_cost
+        );
+        let refund = attached_deposit - required_cost;
+        if refund > 1 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+
+        log_nft_event(
+            "nft_mint",
+            minted_by_owner
+                .into_iter()
+                .map(|(owner_id, token_ids)| json!({"owner_id": owner_id, "token_ids": token_ids}))
+                .collect(),
+        );
+        minted
+    }
+
+    pub fn add_minter(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.Minters.insert(account_id.as_ref())
+    }
+
+    pub fn remove_minter(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.Minters.remove(account_id.as_ref())
+    }
+
+    pub fn get_minters(&self) -> Vec<AccountId> {
+        self.Minters.to_vec()
+    }
 
     pub fn invite_left(&self, account_id:AccountId)->U128{
         let inviteleft = self.InviteNftCounts.get(&account_id).unwrap_or_else(||0);
         return U128::from(inviteleft);
     }
 
-    fn internal_mint(&mut self, token_id :String, token_owner_id : ValidAccountId, token_metadata:Option<TokenMetadata>) ->Token{
+    fn mint_invite_nft(&mut self, invitee: ValidAccountId) -> TokenId {
+        let latest_counter: String = self.tokenIds.get().unwrap();
+        let int_counter: i32 = latest_counter.parse().unwrap();
+        let token_id = (int_counter + 1).to_string();
+
+        let newhash=format!("https://cattery-api.amanraj.dev/api/img/{}", token_id);
+        let media_hash = env::sha256(newhash.clone().as_bytes());
+        let invite_metadata = TokenMetadata {
+            title: Some("invite nft".to_string()),
+            description: Some(format!("invite nft for {}", invitee.to_string())),
+            copies: Some(1),
+            issued_at: None,
+            expires_at: None,
+            starts_at: Some(env::block_timestamp().to_string()),
+            updated_at: Some(env::block_timestamp().to_string()),
+            extra: None,
+            reference: None,
+            reference_hash: None,
+            media: Some(newhash.clone()),
+            media_hash: Some(Base64VecU8::from(media_hash.clone()))
+        };
+
+        self.tokenIds.replace(&token_id);
+        self.internal_mint(token_id.clone(), invitee, Some(invite_metadata), INVITE_CLASS);
+        token_id
+    }
+
+    /// Removes and returns the invite registered for the key that signed this call.
+    fn take_invite_key(&mut self) -> (Vec<u8>, InviteKey) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Claim only can come from this account"
+        );
+        let pk = env::signer_account_pk();
+        let invite = self.InviteKeys.remove(&pk).expect("Unexpected public key");
+        (pk, invite)
+    }
+
+    fn return_invite(&mut self, inviter: &AccountId) {
+        let inviteleft = self.InviteNftCounts.get(inviter).unwrap_or(0);
+        self.InviteNftCounts.insert(inviter, &(inviteleft + 1));
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.ContractGlobal.get().unwrap(),
+            "Only the contract owner can call this method"
+        );
+    }
+
+    /// Mints an owner NFT and gives a first-time owner their invites. Every account holds at
+    /// most one owner NFT.
+    fn internal_mint_owner_nft(&mut self, token_id: TokenId, receiver_id: ValidAccountId, token_metadata: Option<TokenMetadata>) -> Token {
+        let owner_id: AccountId = receiver_id.clone().into();
+        assert!(self.OwnerNftStore.get(&owner_id).is_none(), "{} already has an owner nft", owner_id);
+        let token = self.internal_mint(token_id.clone(), receiver_id, token_metadata, OWNER_CLASS);
+        self.OwnerNftStore.insert(&owner_id, &token_id);
+        if self.InviteNftCounts.get(&owner_id).is_none() {
+            self.InviteNftCounts.insert(&owner_id, &OWNER_NFT_INVITES);
+        }
+        token
+    }
+
+    /// `nft_token` for `&self`; the standard one consumes the contract.
+    fn internal_token(&self, token_id: TokenId) -> Option<Token> {
+        let owner_id = self.tokens.owner_by_id.get(&token_id)?;
+        let metadata = self.tokens.token_metadata_by_id.as_ref().and_then(|by_id| by_id.get(&token_id));
+        let approved_account_ids = self
+            .tokens
+            .approvals_by_id
+            .as_ref()
+            .map(|by_id| by_id.get(&token_id).unwrap_or_default());
+        Some(Token { token_id, owner_id, metadata, approved_account_ids })
+    }
+
+    fn is_soulbound(&self, token_id: &TokenId) -> bool {
+        if let Some(soulbound) = self.SoulboundTokens.get(token_id) {
+            return soulbound;
+        }
+        self.TokenClasses
+            .get(token_id)
+            .and_then(|class| self.Classes.get(&class))
+            .map(|token_class| token_class.soulbound)
+            .unwrap_or(false)
+    }
+
+    fn assert_not_soulbound(&self, token_id: &TokenId) {
+        assert!(!self.is_soulbound(token_id), "Token {} is soulbound and cannot be transferred", token_id);
+    }
+
+    fn assert_minter(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.ContractGlobal.get().unwrap() || self.Minters.contains(&caller),
+            "Only the contract owner or a minter can call this method"
+        );
+    }
+
+    fn internal_mint(&mut self, token_id :String, token_owner_id : ValidAccountId, token_metadata:Option<TokenMetadata>, class: &str) ->Token{
         let initial_storage_usage = env::storage_usage();
         // self.tokens.mint()
         if self.tokens.token_metadata_by_id.is_some() && token_metadata.is_none() {
@@ -313,6 +1001,11 @@ impl Contract {
         if self.tokens.owner_by_id.get(&token_id).is_some() {
             env::panic(b"token_id must be unique");
         }
+        let class = class.to_string();
+        let mut token_class = self.Classes.get(&class).unwrap_or_else(|| env::panic(b"Unknown token class"));
+        token_class.supply = U64(token_class.supply.0 + 1);
+        self.Classes.insert(&class, &token_class);
+        self.TokenClasses.insert(&token_id, &class);
 
         let owner_id: AccountId = token_owner_id.into();
 
@@ -347,9 +1040,110 @@ impl Contract {
     }
 }
 
+/// Logs a NEP-171 event, e.g. `nft_mint`, with the given per-owner entries as `data`.
+fn log_nft_event(event: &str, data: Vec<Value>) {
+    let event = json!({
+        "standard": "nep171",
+        "version": "1.0.0",
+        "event": event,
+        "data": data,
+    });
+    env::log(format!("EVENT_JSON:{}", event.to_string()).as_bytes());
+}
+
+// Core and approval methods are implemented by hand instead of with
+// `impl_non_fungible_token_core!`/`impl_non_fungible_token_approval!` so soulbound tokens
+// can be rejected before they reach `NonFungibleToken`.
+#[near_bindgen]
+impl NonFungibleTokenCore for Contract {
+    #[payable]
+    fn nft_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) {
+        self.assert_not_soulbound(&token_id);
+        self.tokens.nft_transfer(receiver_id, token_id, approval_id, memo)
+    }
+
+    #[payable]
+    fn nft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        self.assert_not_soulbound(&token_id);
+        self.tokens.nft_transfer_call(receiver_id, token_id, approval_id, memo, msg)
+    }
+
+    fn nft_token(self, token_id: TokenId) -> Option<Token> {
+        self.tokens.nft_token(token_id)
+    }
+
+    /// Same as one entry of `nft_batch_mint`, for callers of the standard `mint`.
+    fn mint(
+        &mut self,
+        token_id: TokenId,
+        token_owner_id: ValidAccountId,
+        token_metadata: Option<TokenMetadata>,
+    ) -> Token {
+        self.assert_owner();
+        self.internal_mint_owner_nft(token_id, token_owner_id, token_metadata)
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenResolver for Contract {
+    #[private]
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool {
+        self.tokens.nft_resolve_transfer(previous_owner_id, receiver_id, token_id, approved_account_ids)
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenApproval for Contract {
+    #[payable]
+    fn nft_approve(
+        &mut self,
+        token_id: TokenId,
+        account_id: ValidAccountId,
+        msg: Option<String>,
+    ) -> Option<Promise> {
+        self.assert_not_soulbound(&token_id);
+        self.tokens.nft_approve(token_id, account_id, msg)
+    }
+
+    #[payable]
+    fn nft_revoke(&mut self, token_id: TokenId, account_id: ValidAccountId) {
+        self.tokens.nft_revoke(token_id, account_id)
+    }
+
+    #[payable]
+    fn nft_revoke_all(&mut self, token_id: TokenId) {
+        self.tokens.nft_revoke_all(token_id)
+    }
+
+    fn nft_is_approved(
+        self,
+        token_id: TokenId,
+        approved_account_id: ValidAccountId,
+        approval_id: Option<u64>,
+    ) -> bool {
+        self.tokens.nft_is_approved(token_id, approved_account_id, approval_id)
+    }
+}
 
-near_contract_standards::impl_non_fungible_token_core!(Contract, tokens);
-near_contract_standards::impl_non_fungible_token_approval!(Contract, tokens);
 near_contract_standards::impl_non_fungible_token_enumeration!(Contract, tokens);
 
 #[near_bindgen]
@@ -359,3 +1153,537 @@ impl NonFungibleTokenMetadataProvider for Contract {
     }
 }
 
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::MockedBlockchain;
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn sample_metadata() -> TokenMetadata {
+        TokenMetadata {
+            title: Some("owner nft".to_string()),
+            description: None,
+            media: None,
+            media_hash: None,
+            copies: Some(1),
+            issued_at: None,
+            expires_at: None,
+            starts_at: None,
+            updated_at: None,
+            extra: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+
+    /// A contract owned by `accounts(1)` where `accounts(2)` holds owner NFT "1".
+    fn setup_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1), "nft".to_string(), "NFT".to_string(), "https://example.com".to_string());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000_000)
+            .build());
+        contract.nft_batch_mint(vec![(accounts(2), sample_metadata())], None);
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).build());
+        contract
+    }
+
+    fn pool_metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "pool".to_string(),
+            symbol: "POOL".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 0,
+        }
+    }
+
+    /// Escrows owner NFT "1" of `accounts(2)` for the pool factory `accounts(4)`.
+    fn create_pool(context: &mut VMContextBuilder, contract: &mut Contract) {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_pool_factory(accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(5).build());
+        contract.create_pool(accounts(4).into(), U128(100), pool_metadata(), None);
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).build());
+    }
+
+    fn callback(context: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "already has an owner nft")]
+    fn test_batch_mint_rejects_second_owner_nft() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(10_000_000_000_000_000_000_000_000).build());
+        contract.nft_batch_mint(vec![(accounts(2), sample_metadata())], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 50 tokens can be minted per batch")]
+    fn test_batch_mint_cap() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(10_000_000_000_000_000_000_000_000).build());
+        contract.nft_batch_mint(vec![(accounts(3), sample_metadata()); MAX_BATCH_MINT + 1], Some(INVITE_CLASS.to_string()));
+    }
+
+    #[test]
+    fn test_batch_mint_charges_storage_and_refunds_the_rest() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let deposit = 10_000_000_000_000_000_000_000_000;
+        let balance = 100_000_000_000_000_000_000_000_000;
+        testing_env!(context.account_balance(balance).attached_deposit(deposit).build());
+        let initial_storage_usage = env::storage_usage();
+        contract.nft_batch_mint(vec![(accounts(3), sample_metadata()), (accounts(4), sample_metadata())], None);
+
+        let storage_cost = Balance::from(env::storage_usage() - initial_storage_usage) * env::storage_byte_cost();
+        assert!(storage_cost > 0);
+        assert_eq!(balance + deposit - env::account_balance(), deposit - storage_cost);
+    }
+
+    #[test]
+    #[should_panic(expected = "to cover storage")]
+    fn test_batch_mint_requires_storage_deposit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(1).build());
+        contract.nft_batch_mint(vec![(accounts(3), sample_metadata())], None);
+    }
+
+    #[test]
+    fn test_batch_mint_emits_one_event() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.attached_deposit(10_000_000_000_000_000_000_000_000).build());
+        let minted = contract.nft_batch_mint(
+            vec![(accounts(3), sample_metadata()), (accounts(4), sample_metadata()), (accounts(3), sample_metadata())],
+            Some(INVITE_CLASS.to_string()),
+        );
+
+        let events: Vec<String> = get_logs().into_iter().filter(|log| log.starts_with("EVENT_JSON:")).collect();
+        assert_eq!(events.len(), 1);
+        let event: Value = near_sdk::serde_json::from_str(&events[0]["EVENT_JSON:".len()..]).unwrap();
+        assert_eq!(event["event"], "nft_mint");
+        assert_eq!(
+            event["data"],
+            json!([
+                {"owner_id": accounts(3), "token_ids": [minted[0], minted[2]]},
+                {"owner_id": accounts(4), "token_ids": [minted[1]]},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_admin_recover_token_moves_owner_nft() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        contract.admin_recover_token("1".to_string(), accounts(3));
+
+        assert_eq!(contract.nft_token_view("1".to_string()).unwrap().token.owner_id, accounts(3).to_string());
+        assert_eq!(contract.OwnerNftStore.get(accounts(3).as_ref()), Some("1".to_string()));
+        assert!(contract.OwnerNftStore.get(accounts(2).as_ref()).is_none());
+    }
+
+    #[test]
+    fn test_admin_recover_escrowed_token() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        create_pool(&mut context, &mut contract);
+        assert_eq!(contract.nft_token_view("1".to_string()).unwrap().token.owner_id, accounts(0).to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.admin_recover_token("1".to_string(), accounts(3));
+        assert!(contract.get_pool_escrow(accounts(2).into()).is_none());
+        assert_eq!(contract.OwnerNftStore.get(accounts(3).as_ref()), Some("1".to_string()));
+        assert_eq!(contract.nft_token_view("1".to_string()).unwrap().token.owner_id, accounts(3).to_string());
+    }
+
+    #[test]
+    fn test_mint_registered_class() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        contract.register_class("badge".to_string(), true);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000_000)
+            .build());
+        let minted = contract.nft_batch_mint(vec![(accounts(3), sample_metadata())], Some("badge".to_string()));
+
+        assert_eq!(contract.nft_class_of(minted[0].clone()), Some("badge".to_string()));
+        assert_eq!(contract.nft_supply_for_class("badge".to_string()).0, 1);
+        assert!(contract.nft_is_soulbound(minted[0].clone()));
+        assert!(contract.OwnerNftStore.get(accounts(3).as_ref()).is_none());
+    }
+
+    #[test]
+    fn test_set_soulbound_overrides_class() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let invite_id = contract.mint_invite_nft(accounts(3));
+        assert!(!contract.nft_is_soulbound(invite_id.clone()));
+        contract.set_soulbound(invite_id.clone(), true);
+        assert!(contract.nft_is_soulbound(invite_id.clone()));
+        contract.clear_soulbound(invite_id.clone());
+        assert!(!contract.nft_is_soulbound(invite_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "owner nfts are always soulbound")]
+    fn test_owner_nft_cannot_be_made_transferable() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        contract.set_soulbound("1".to_string(), false);
+    }
+
+    #[test]
+    #[should_panic(expected = "owner nfts are always soulbound")]
+    fn test_owner_class_cannot_be_made_transferable() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        contract.set_class_soulbound(OWNER_CLASS.to_string(), false);
+    }
+
+    /// `accounts(2)` offers an invite to `accounts(3)`.
+    fn invite_other(context: &mut VMContextBuilder, contract: &mut Contract) -> U64 {
+        testing_env!(context.predecessor_account_id(accounts(2)).block_timestamp(0).build());
+        let offer_id = contract.invite_other(accounts(3));
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES - 1);
+        offer_id
+    }
+
+    #[test]
+    fn test_accept_invite_mints_invite_nft() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_invite(offer_id);
+        assert!(contract.get_invite_offer(offer_id).is_none());
+
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        let offer = InviteOffer { inviter: accounts(2).into(), invitee: accounts(3).into(), expires_at: U64(DEFAULT_INVITE_TTL) };
+        let token_id = contract.on_invite_accepted(offer_id, offer).unwrap();
+        assert_eq!(contract.nft_token_view(token_id.clone()).unwrap().token.owner_id, accounts(3).to_string());
+        assert_eq!(contract.nft_class_of(token_id), Some(INVITE_CLASS.to_string()));
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES - 1);
+    }
+
+    #[test]
+    fn test_failed_invite_payment_keeps_the_offer() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_invite(offer_id);
+        callback(&mut context, PromiseResult::Failed);
+        let offer = InviteOffer { inviter: accounts(2).into(), invitee: accounts(3).into(), expires_at: U64(DEFAULT_INVITE_TTL) };
+        assert_eq!(contract.on_invite_accepted(offer_id, offer), None);
+
+        assert_eq!(contract.get_invite_offer(offer_id).unwrap().invitee, accounts(3).to_string());
+        assert_eq!(contract.nft_supply_for_class(INVITE_CLASS.to_string()).0, 0);
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES - 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the invitee can accept this offer")]
+    fn test_accept_invite_only_invitee() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.accept_invite(offer_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "invite offer has expired")]
+    fn test_accept_expired_invite() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(accounts(3)).block_timestamp(DEFAULT_INVITE_TTL).build());
+        contract.accept_invite(offer_id);
+    }
+
+    #[test]
+    fn test_decline_invite_returns_it() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.decline_invite(offer_id);
+        assert!(contract.get_invite_offer(offer_id).is_none());
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the invitee can decline this offer")]
+    fn test_decline_invite_only_invitee() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+        contract.decline_invite(offer_id);
+    }
+
+    #[test]
+    fn test_reclaim_expired_invite() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(accounts(4)).block_timestamp(DEFAULT_INVITE_TTL).build());
+        contract.reclaim_invite(offer_id);
+        assert!(contract.get_invite_offer(offer_id).is_none());
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES);
+    }
+
+    #[test]
+    #[should_panic(expected = "invite offer has not expired yet")]
+    fn test_reclaim_pending_invite() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let offer_id = invite_other(&mut context, &mut contract);
+        contract.reclaim_invite(offer_id);
+    }
+
+    fn invite_key() -> Base58PublicKey {
+        Base58PublicKey::try_from("ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp".to_string()).unwrap()
+    }
+
+    fn invite_key_entry() -> InviteKey {
+        InviteKey { inviter: accounts(2).into(), deposit: U128(ACCESS_KEY_ALLOWANCE + 10) }
+    }
+
+    /// Actions of the receipts created by the last call, e.g. to look for a `DeleteKey`.
+    fn created_actions() -> String {
+        near_sdk::serde_json::to_string(&near_sdk::test_utils::get_created_receipts()).unwrap()
+    }
+
+    /// `accounts(2)` registers `invite_key()` with 10 yoctoNEAR for the invitee.
+    fn invite_with_key(context: &mut VMContextBuilder, contract: &mut Contract) {
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(ACCESS_KEY_ALLOWANCE + 10).build());
+        contract.invite_with_key(invite_key());
+        assert!(contract.get_invite_key(invite_key()).is_none());
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).build());
+        callback(context, PromiseResult::Successful(vec![]));
+        assert!(contract.on_invite_key_added(invite_key(), invite_key_entry()));
+        testing_env!(context.storage_usage(env::storage_usage()).build());
+    }
+
+    /// Sets up the next call the way the invite key holder signs it.
+    fn sign_with_invite_key(context: &mut VMContextBuilder) {
+        testing_env!(context.predecessor_account_id(accounts(0)).signer_account_pk(invite_key().into()).build());
+    }
+
+    #[test]
+    fn test_invite_with_key_registers_added_key() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        invite_with_key(&mut context, &mut contract);
+        assert_eq!(contract.get_invite_key(invite_key()).unwrap().inviter, accounts(2).to_string());
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES - 1);
+    }
+
+    #[test]
+    fn test_invite_key_that_could_not_be_added_is_refunded() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(ACCESS_KEY_ALLOWANCE + 10).build());
+        contract.invite_with_key(invite_key());
+
+        let balance = env::account_balance();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).account_balance(balance).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert!(!contract.on_invite_key_added(invite_key(), invite_key_entry()));
+        assert_eq!(balance - env::account_balance(), ACCESS_KEY_ALLOWANCE + 10);
+        assert!(contract.get_invite_key(invite_key()).is_none());
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES);
+    }
+
+    #[test]
+    fn test_claim_invite() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        invite_with_key(&mut context, &mut contract);
+
+        sign_with_invite_key(&mut context);
+        let token_id = contract.claim_invite(accounts(3));
+        assert_eq!(contract.nft_token_view(token_id).unwrap().token.owner_id, accounts(3).to_string());
+        assert!(contract.get_invite_key(invite_key()).is_none());
+        assert!(created_actions().contains("DeleteKey"));
+    }
+
+    #[test]
+    fn test_create_account_and_claim_invite() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        invite_with_key(&mut context, &mut contract);
+
+        sign_with_invite_key(&mut context);
+        contract.create_account_and_claim_invite(accounts(3), invite_key());
+        assert!(contract.get_invite_key(invite_key()).is_none());
+        assert!(!created_actions().contains("DeleteKey"));
+
+        callback(&mut context, PromiseResult::Successful(b"true".to_vec()));
+        let token_id = contract.on_account_created_and_claimed(accounts(3).into(), invite_key(), invite_key_entry()).unwrap();
+        assert_eq!(contract.nft_token_view(token_id).unwrap().token.owner_id, accounts(3).to_string());
+        assert!(created_actions().contains("DeleteKey"));
+    }
+
+    #[test]
+    fn test_failed_account_creation_keeps_the_key() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        invite_with_key(&mut context, &mut contract);
+
+        sign_with_invite_key(&mut context);
+        contract.create_account_and_claim_invite(accounts(3), invite_key());
+        callback(&mut context, PromiseResult::Successful(b"false".to_vec()));
+        assert_eq!(contract.on_account_created_and_claimed(accounts(3).into(), invite_key(), invite_key_entry()), None);
+
+        assert_eq!(contract.get_invite_key(invite_key()).unwrap().deposit.0, ACCESS_KEY_ALLOWANCE + 10);
+        assert_eq!(created_actions(), "[]");
+        assert_eq!(contract.nft_supply_for_class(INVITE_CLASS.to_string()).0, 0);
+    }
+
+    #[test]
+    fn test_cancel_invite_key() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        invite_with_key(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let balance = env::account_balance();
+        contract.cancel_invite_key(invite_key());
+        assert_eq!(balance - env::account_balance(), 10);
+        assert!(contract.get_invite_key(invite_key()).is_none());
+        assert_eq!(contract.invite_left(accounts(2).into()).0, OWNER_NFT_INVITES);
+        assert!(created_actions().contains("DeleteKey"));
+    }
+
+    #[test]
+    #[should_panic(expected = "only the inviter can cancel this invite")]
+    fn test_cancel_invite_key_only_inviter() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        invite_with_key(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.cancel_invite_key(invite_key());
+    }
+
+    #[test]
+    #[should_panic(expected = "invite key not found")]
+    fn test_cancel_invite_key_that_was_not_added() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(ACCESS_KEY_ALLOWANCE + 10).build());
+        contract.invite_with_key(invite_key());
+        callback(&mut context, PromiseResult::Failed);
+        contract.on_invite_key_added(invite_key(), invite_key_entry());
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        contract.cancel_invite_key(invite_key());
+    }
+
+    #[test]
+    fn test_invite_nft_does_not_replace_owner_nft() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let invite_id = contract.mint_invite_nft(accounts(2));
+
+        assert_eq!(contract.nft_class_of(invite_id), Some(INVITE_CLASS.to_string()));
+        assert_eq!(contract.OwnerNftStore.get(accounts(2).as_ref()), Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_create_pool_passes_owner_vesting() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        contract.set_pool_factory(accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(5).build());
+        let vesting = VestingSchedule { amount: U128(60), start: U64(0), cliff: U64(100), end: U64(1_000) };
+        contract.create_pool(accounts(4).into(), U128(100), pool_metadata(), Some(vesting));
+
+        let receipts: Value = near_sdk::serde_json::from_str(&created_actions()).unwrap();
+        let new_pool = &receipts[0]["actions"][0]["FunctionCall"];
+        assert_eq!(new_pool["method_name"], "new_pool");
+        let args: Value = near_sdk::serde_json::from_str(new_pool["args"].as_str().unwrap()).unwrap();
+        assert_eq!(args["owner_vesting"], json!({ "amount": "60", "start": "0", "cliff": "100", "end": "1000" }));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not the pool factory")]
+    fn test_create_pool_rejects_unknown_factory() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        contract.set_pool_factory(accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.create_pool(accounts(3).into(), U128(100), pool_metadata(), None);
+    }
+
+    #[test]
+    fn test_on_create_pool_failure_releases_escrow() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        create_pool(&mut context, &mut contract);
+
+        callback(&mut context, PromiseResult::Failed);
+        assert_eq!(contract.on_create_pool(accounts(2).into(), U128(5)), None);
+        assert!(contract.get_pool_escrow(accounts(2).into()).is_none());
+        assert!(contract.EscrowOwners.get(&"1".to_string()).is_none());
+        assert_eq!(contract.nft_token_view("1".to_string()).unwrap().token.owner_id, accounts(2).to_string());
+    }
+
+    #[test]
+    fn test_on_create_pool_success_keeps_escrow() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        create_pool(&mut context, &mut contract);
+
+        let pool_id = format!("pool.{}", accounts(4));
+        callback(&mut context, PromiseResult::Successful(near_sdk::serde_json::to_vec(&Some(pool_id.clone())).unwrap()));
+        assert_eq!(contract.on_create_pool(accounts(2).into(), U128(5)), Some(pool_id));
+        assert_eq!(contract.get_pool_escrow(accounts(2).into()).unwrap().token_id, "1".to_string());
+        assert_eq!(contract.nft_token_view("1".to_string()).unwrap().token.owner_id, accounts(0).to_string());
+    }
+
+    #[test]
+    fn test_release_escrowed_nft_by_factory() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        create_pool(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.release_escrowed_nft(accounts(2).into());
+        assert!(contract.get_pool_escrow(accounts(2).into()).is_none());
+        assert_eq!(contract.nft_token_view("1".to_string()).unwrap().token.owner_id, accounts(2).to_string());
+    }
+}
@@ -1,89 +1,1211 @@
 // use near_contract_standards::non_fungible_token::core::NonFungibleTokenReceiver;
 // use near_contract_standards::fungi::TokenId;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{U128, ValidAccountId};
-use near_sdk::{env, ext_contract, log, near_bindgen, setup_alloc, init, AccountId, Balance, Gas, PanicOnDefault, PromiseOrValue, Promise};
-use near_sdk::collections::{LazyOption, LookupMap};
+use near_sdk::json_types::{Base58PublicKey, Base64VecU8, U128, U64, ValidAccountId};
+use near_sdk::{env, ext_contract, log, near_bindgen, setup_alloc, AccountId, Balance, Gas, PanicOnDefault, PromiseOrValue, Promise, PromiseResult};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
 // use near_sdk::PromiseOrValue::Promise;
-use near_sdk::serde_json::{json, Value};
-use near_sdk::serde_json::Value::String;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
 
 setup_alloc!();
 
-const CODE :&[u8]= include_bytes!("../../res/fungible_token.wasm");
-const NO_DEPOSIT: Balance = 5226110000000000000000000;
+/// Minimum deposit `new_pool` must be called with. It funds the pool subaccount.
+const POOL_ACCOUNT_DEPOSIT: Balance = 5226110000000000000000000;
 const BASE_GAS: Gas = 5_000_000_000_000;
 const PROMISE_CALL: Gas = 5_000_000_000_000;
 const GAS_FOR_NFT_ON_APPROVE: Gas = BASE_GAS + PROMISE_CALL;
+const GAS_FOR_POOL_INIT: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_POOL_CREATED: Gas = 30_000_000_000_000;
+const GAS_FOR_RELEASE_NFT: Gas = 10_000_000_000_000;
+const POOL_ADMIN_KEY_ALLOWANCE: Balance = 250_000_000_000_000_000_000_000;
+const GAS_FOR_POOL_UPGRADE: Gas = 50_000_000_000_000;
+const GAS_FOR_ON_POOL_UPGRADED: Gas = 10_000_000_000_000;
+const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+const GAS_FOR_ON_FT_WITHDRAWN: Gas = 10_000_000_000_000;
+const GAS_FOR_POOL_CLOSE: Gas = 20_000_000_000_000;
+const GAS_FOR_ON_POOL_CLOSED: Gas = 20_000_000_000_000;
+/// Each upgrade carries the whole code blob, so only a few pools fit in one call.
+const MAX_UPGRADE_BATCH: usize = 4;
 
 trait DeployPool {
-    fn new_pool(&mut self, poolname:AccountId, owner_id:AccountId,roomsize :U128) -> PromiseOrValue<AccountId>;
+    fn new_pool(&mut self, poolname:AccountId, owner_id:AccountId,roomsize :U128, metadata: FungibleTokenMetadata, code_version: Option<u32>, owner_vesting: Option<VestingSchedule>) -> PromiseOrValue<Option<AccountId>>;
+}
+
+#[ext_contract(ext_self)]
+pub trait PoolCallbacks {
+    fn on_pool_created(&mut self, pool_id: AccountId, payment: PoolPayment) -> Option<AccountId>;
+    fn on_ft_treasury_withdrawn(&mut self, token_id: AccountId, amount: U128) -> bool;
+    fn on_ft_fee_credit_withdrawn(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) -> bool;
+    fn on_pool_upgraded(&mut self, pool_id: AccountId, code_version: u32) -> bool;
+    fn on_pool_closed(&mut self, pool_id: AccountId) -> bool;
+}
+
+#[ext_contract(ext_nft)]
+pub trait NftContract {
+    fn release_escrowed_nft(&mut self, owner_id: AccountId);
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Pool {
+    /// Manages code, key policy, authorized callers and operators.
     owner: AccountId,
-    subowner :AccountId,
-    token: LookupMap<AccountId,AccountId>
+    /// Set by `propose_owner` until the proposed account calls `accept_ownership`.
+    pending_owner: Option<AccountId>,
+    /// Can create and close pools.
+    operators: UnorderedSet<AccountId>,
+    token: LookupMap<AccountId,AccountId>,
+    pools: UnorderedMap<AccountId,PoolInfo>,
+    pools_by_owner: LookupMap<AccountId,UnorderedSet<AccountId>>,
+    authorized_callers: UnorderedSet<AccountId>,
+    pool_key_policy: PoolKeyPolicy,
+    pool_code: LookupMap<u32,Vec<u8>>,
+    code_versions: UnorderedMap<u32,CodeVersion>,
+    next_code_version: u32,
+    default_code_version: Option<u32>,
+    /// Receives the creation fees collected in `treasury_balance` and `ft_treasury`.
+    treasury_id: AccountId,
+    creation_fee: Balance,
+    treasury_balance: Balance,
+    ft_fee: Option<FtFee>,
+    /// FT sent with `ft_transfer_call` towards the FT creation fee, per fee token and creator.
+    ft_fee_credits: LookupMap<(AccountId, AccountId),Balance>,
+    /// Collected FT creation fees per fee token.
+    ft_treasury: UnorderedMap<AccountId,Balance>
+}
+
+/// Creation fee paid in a fungible token on top of the NEAR fee.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtFee {
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+/// What `create_pool` costs. `total` is the NEAR deposit to attach; `ft_fee` has to be sent
+/// to the factory with `ft_transfer_call` beforehand.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolCreationCost {
+    pub account_deposit: U128,
+    pub creation_fee: U128,
+    pub total: U128,
+    pub ft_fee: Option<FtFee>,
+}
+
+/// What was paid for a new pool. `deposit` includes the NEAR `fee`; `ft_fee` was taken from
+/// the FT fee credits of `payer`, who gets everything back if the pool can't be created.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolPayment {
+    pub payer: AccountId,
+    pub deposit: U128,
+    pub fee: U128,
+    pub ft_fee: Option<FtFee>,
+}
+
+/// Vesting of the creator's pool tokens, passed to the pool's `new` as `owner_vesting`.
+/// Times are block timestamps in nanoseconds; the pool checks the schedule.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingSchedule {
+    pub amount: U128,
+    pub start: U64,
+    pub cliff: U64,
+    pub end: U64,
+}
+
+/// Pool token settings `internal_new_pool` initializes a new pool with.
+struct NewPool {
+    poolname: AccountId,
+    owner_id: AccountId,
+    roomsize: U128,
+    metadata: FungibleTokenMetadata,
+    code_version: Option<u32>,
+    owner_vesting: Option<VestingSchedule>,
 }
 
+/// A pool contract code blob uploaded with `store_pool_code`. `hash` is the sha256 of the
+/// code, so deployed pools can be checked against it.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CodeVersion {
+    pub hash: Base64VecU8,
+    pub size: U64,
+    pub uploaded_at: U64,
+}
+
+/// Access keys added to new pool subaccounts.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PoolKeyPolicy {
+    /// The pool has no access keys, so its code and state can't be touched by anyone.
+    NoKeys,
+    /// A function-call key that can only call `method_names` (comma separated) on the pool.
+    FunctionCallKey { public_key: Base58PublicKey, method_names: String },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PoolStatus {
+    /// Subaccount creation and initialization are still in flight.
+    Pending,
+    Active,
+    /// `close_pool` is deleting the pool subaccount.
+    Closing,
+    Closed,
+}
+
+/// Registry entry of a deployed pool. `ft_contract` is the pool subaccount, which runs the
+/// pool's fungible token contract. `nft_escrowed` is set when `nft_contract` holds the
+/// creator's owner NFT for this pool, i.e. the pool was created through `new_pool`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolInfo {
+    pub name: AccountId,
+    pub ft_contract: AccountId,
+    pub creator: AccountId,
+    pub nft_contract: AccountId,
+    pub nft_escrowed: bool,
+    pub created_at: U64,
+    pub room_size: U128,
+    pub status: PoolStatus,
+    pub key_policy: PoolKeyPolicy,
+    pub code_version: u32,
+    pub last_upgrade: Option<PoolUpgrade>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum UpgradeStatus {
+    Pending,
+    Upgraded,
+    Failed,
+}
+
+/// Latest attempt to move a pool to `code_version` with `upgrade_pools`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolUpgrade {
+    pub code_version: u32,
+    pub status: UpgradeStatus,
+    pub updated_at: U64,
+}
 
 
 #[near_bindgen]
 impl Pool{
 
     #[init]
-    pub fn new(subowner : AccountId)->Self{
-        Self{
+    pub fn new(operator_id : ValidAccountId)->Self{
+        let mut this = Self{
             owner:env::predecessor_account_id(),
-            subowner,
+            pending_owner: None,
+            operators: UnorderedSet::new(b"r"),
             token: LookupMap::new(b"a"),
+            pools: UnorderedMap::new(b"p"),
+            pools_by_owner: LookupMap::new(b"o"),
+            authorized_callers: UnorderedSet::new(b"c"),
+            pool_key_policy: PoolKeyPolicy::NoKeys,
+            pool_code: LookupMap::new(b"w"),
+            code_versions: UnorderedMap::new(b"k"),
+            next_code_version: 0,
+            default_code_version: None,
+            treasury_id: env::predecessor_account_id(),
+            creation_fee: 0,
+            treasury_balance: 0,
+            ft_fee: None,
+            ft_fee_credits: LookupMap::new(b"f"),
+            ft_treasury: UnorderedMap::new(b"t"),
+        };
+        this.operators.insert(operator_id.as_ref());
+        this
+    }
+
+    /// First step of an ownership transfer. The owner stays in charge until `new_owner`
+    /// calls `accept_ownership`.
+    pub fn propose_owner(&mut self, new_owner: ValidAccountId) {
+        self.assert_owner();
+        self.pending_owner = Some(new_owner.into());
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let caller = env::predecessor_account_id();
+        assert_eq!(self.pending_owner.as_ref(), Some(&caller), "only the proposed owner can accept ownership");
+        log!("factory ownership moved from {} to {}", self.owner, caller);
+        self.owner = caller;
+        self.pending_owner = None;
+    }
+
+    pub fn add_operator(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.operators.insert(account_id.as_ref())
+    }
+
+    pub fn remove_operator(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.operators.remove(account_id.as_ref())
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    pub fn get_operators(&self) -> Vec<AccountId> {
+        self.operators.to_vec()
+    }
+
+    /// Allows `account_id` (the NFT contract) to call `new_pool`.
+    pub fn add_authorized_caller(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.authorized_callers.insert(account_id.as_ref())
+    }
+
+    pub fn remove_authorized_caller(&mut self, account_id: ValidAccountId) -> bool {
+        self.assert_owner();
+        self.authorized_callers.remove(account_id.as_ref())
+    }
+
+    pub fn get_authorized_callers(&self) -> Vec<AccountId> {
+        self.authorized_callers.to_vec()
+    }
+
+    /// Sets the access keys that pools created from now on get.
+    pub fn set_pool_key_policy(&mut self, policy: PoolKeyPolicy) {
+        self.assert_owner();
+        if let PoolKeyPolicy::FunctionCallKey { method_names, .. } = &policy {
+            assert!(!method_names.is_empty(), "a function-call key needs at least one method");
         }
+        self.pool_key_policy = policy;
     }
 
-    pub fn change_owner(&mut self,subowner:AccountId) ->bool{
-        assert_eq!(env::predecessor_account_id(), self.owner);
-        self.subowner=subowner;
-        return true;
+    pub fn get_pool_key_policy(&self) -> PoolKeyPolicy {
+        self.pool_key_policy.clone()
     }
 
-    pub fn get_pool_details(&self, for_account : AccountId)-> AccountId{
-        return self.token.get(&for_account).unwrap();
+    /// Stores the pool contract code passed as the raw input of this call and returns its
+    /// version. The attached deposit must cover the storage of the code; the rest is refunded.
+    /// The new version becomes the default for `new_pool` unless `set_default_code_version`
+    /// picks another one. `upload_pool_code.js` uploads a wasm file this way.
+    #[payable]
+    pub fn store_pool_code(&mut self) -> u32 {
+        self.assert_owner();
+        let initial_storage_usage = env::storage_usage();
+        let code = env::input().expect("expected pool code as input");
+        assert!(!code.is_empty(), "expected pool code as input");
+
+        let version = self.next_code_version;
+        self.next_code_version += 1;
+        self.code_versions.insert(&version, &CodeVersion {
+            hash: Base64VecU8::from(env::sha256(&code)),
+            size: U64(code.len() as u64),
+            uploaded_at: U64(env::block_timestamp()),
+        });
+        self.pool_code.insert(&version, &code);
+        self.default_code_version = Some(version);
+
+        let required_cost = Balance::from(env::storage_usage() - initial_storage_usage) * env::storage_byte_cost();
+        let attached_deposit = env::attached_deposit();
+        assert!(
+            attached_deposit >= required_cost,
+            "Must attach {} yoctoNEAR to cover storage",
+            required_cost
+        );
+        let refund = attached_deposit - required_cost;
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        log!("stored pool code version {}", version);
+        version
+    }
+
+    /// Removes a stored code version. Pools already deployed with it are not affected.
+    pub fn remove_pool_code(&mut self, version: u32) {
+        self.assert_owner();
+        assert_ne!(self.default_code_version, Some(version), "cannot remove the default code version");
+        let code = self.pool_code.remove(&version).expect("code version not found");
+        self.code_versions.remove(&version);
+        let refund = Balance::from(code.len() as u64) * env::storage_byte_cost();
+        Promise::new(self.owner.clone()).transfer(refund);
+    }
+
+    pub fn set_default_code_version(&mut self, version: u32) {
+        self.assert_owner();
+        assert!(self.code_versions.get(&version).is_some(), "code version not found");
+        self.default_code_version = Some(version);
+    }
+
+    pub fn get_default_code_version(&self) -> Option<u32> {
+        self.default_code_version
+    }
+
+    pub fn get_code_version(&self, version: u32) -> Option<CodeVersion> {
+        self.code_versions.get(&version)
+    }
+
+    pub fn get_code_versions(&self) -> Vec<(u32, CodeVersion)> {
+        self.code_versions.to_vec()
+    }
+
+    /// Deploys stored code `code_version` to each of `pool_ids` through the pool's `upgrade`
+    /// method, which also runs its `migrate`. The outcome per pool is recorded in its registry
+    /// entry as `last_upgrade`. Pools deployed before the pool token had an `upgrade` method
+    /// can't be upgraded this way: the call fails and the upgrade is marked `Failed`.
+    pub fn upgrade_pools(&mut self, pool_ids: Vec<AccountId>, code_version: u32) {
+        self.assert_owner();
+        assert!(!pool_ids.is_empty(), "no pools to upgrade");
+        assert!(pool_ids.len() <= MAX_UPGRADE_BATCH, "at most {} pools can be upgraded per call", MAX_UPGRADE_BATCH);
+        let code = self.pool_code.get(&code_version).expect("code version not found");
+
+        for pool_id in pool_ids {
+            let mut info = self.pools.get(&pool_id).expect("pool not found");
+            assert_eq!(info.status, PoolStatus::Active, "pool {} is not active", pool_id);
+            if let Some(upgrade) = &info.last_upgrade {
+                assert_ne!(upgrade.status, UpgradeStatus::Pending, "pool {} is already being upgraded", pool_id);
+            }
+            info.last_upgrade = Some(PoolUpgrade {
+                code_version,
+                status: UpgradeStatus::Pending,
+                updated_at: U64(env::block_timestamp()),
+            });
+            self.pools.insert(&pool_id, &info);
+
+            Promise::new(pool_id.clone())
+                .function_call(b"upgrade".to_vec(), code.clone(), 0, GAS_FOR_POOL_UPGRADE)
+                .then(ext_self::on_pool_upgraded(
+                    pool_id,
+                    code_version,
+                    &env::current_account_id(),
+                    0,
+                    GAS_FOR_ON_POOL_UPGRADED,
+                ));
+        }
+    }
+
+    #[private]
+    pub fn on_pool_upgraded(&mut self, pool_id: AccountId, code_version: u32) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        let mut info = self.pools.get(&pool_id).expect("pool not found");
+        let upgraded = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => true,
+            PromiseResult::Failed => false,
+        };
+        if upgraded {
+            info.code_version = code_version;
+        } else {
+            log!("upgrading pool {} to code version {} failed", pool_id, code_version);
+        }
+        info.last_upgrade = Some(PoolUpgrade {
+            code_version,
+            status: if upgraded { UpgradeStatus::Upgraded } else { UpgradeStatus::Failed },
+            updated_at: U64(env::block_timestamp()),
+        });
+        self.pools.insert(&pool_id, &info);
+        upgraded
+    }
+
+    /// Creates a pool for `owner_id` without escrowing an owner NFT. Only operators may call
+    /// this, and `nft_contract` has to be an authorized caller, as the pool token lets it move
+    /// tokens. The deposit works like in `new_pool`, but the operator pays the fees, including
+    /// the FT fee from their own credits, and gets them back if the pool can't be created.
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn operator_new_pool(
+        &mut self,
+        poolname: AccountId,
+        owner_id: ValidAccountId,
+        roomsize: U128,
+        metadata: FungibleTokenMetadata,
+        code_version: Option<u32>,
+        nft_contract: ValidAccountId,
+        owner_vesting: Option<VestingSchedule>,
+    ) -> Promise {
+        assert!(self.is_operator(&env::predecessor_account_id()), "only operators can call this method");
+        assert!(
+            self.authorized_callers.contains(nft_contract.as_ref()),
+            "{} is not an authorized nft contract",
+            nft_contract
+        );
+        let payer = env::predecessor_account_id();
+        let pool = NewPool { poolname, owner_id: owner_id.into(), roomsize, metadata, code_version, owner_vesting };
+        self.internal_new_pool(pool, nft_contract.into(), false, payer)
+    }
+
+    /// Closes an active pool. The pool deletes its subaccount and sends the remaining balance
+    /// to `beneficiary_id` (the creator by default), which only works once the whole pool
+    /// token supply is back with the creator or burned. Afterwards the NFT contract releases
+    /// the creator's escrowed owner NFT.
+    pub fn close_pool(&mut self, pool_id: AccountId, beneficiary_id: Option<ValidAccountId>) -> Promise {
+        let mut info = self.pools.get(&pool_id).expect("pool not found");
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == info.creator || self.is_operator(&caller),
+            "only the pool creator or an operator can close this pool"
+        );
+        assert_eq!(info.status, PoolStatus::Active, "pool {} is not active", pool_id);
+        let beneficiary_id: AccountId = beneficiary_id.map(|account_id| account_id.into()).unwrap_or_else(|| info.creator.clone());
+
+        info.status = PoolStatus::Closing;
+        self.pools.insert(&pool_id, &info);
+
+        Promise::new(pool_id.clone())
+            .function_call(
+                b"close".to_vec(),
+                json!({"owner_id": info.creator, "beneficiary_id": beneficiary_id}).to_string().into_bytes(),
+                0,
+                GAS_FOR_POOL_CLOSE,
+            )
+            .then(ext_self::on_pool_closed(
+                pool_id,
+                &env::current_account_id(),
+                0,
+                GAS_FOR_ON_POOL_CLOSED,
+            ))
+    }
+
+    #[private]
+    pub fn on_pool_closed(&mut self, pool_id: AccountId) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        let mut info = self.pools.get(&pool_id).expect("pool not found");
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                info.status = PoolStatus::Closed;
+                self.pools.insert(&pool_id, &info);
+                if self.token.get(&info.creator).as_ref() == Some(&pool_id) {
+                    self.token.remove(&info.creator);
+                }
+                if info.nft_escrowed {
+                    ext_nft::release_escrowed_nft(info.creator, &info.nft_contract, 0, GAS_FOR_RELEASE_NFT);
+                }
+                true
+            }
+            PromiseResult::Failed => {
+                log!("closing pool {} failed", pool_id);
+                info.status = PoolStatus::Active;
+                self.pools.insert(&pool_id, &info);
+                false
+            }
+        }
+    }
+
+    pub fn set_treasury(&mut self, treasury_id: ValidAccountId) {
+        self.assert_owner();
+        self.treasury_id = treasury_id.into();
+    }
+
+    /// Sets the NEAR fee charged on top of `POOL_ACCOUNT_DEPOSIT` for each new pool.
+    pub fn set_creation_fee(&mut self, creation_fee: U128) {
+        self.assert_owner();
+        self.creation_fee = creation_fee.0;
+    }
+
+    /// Sets or removes the creation fee in a fungible token. Credits and fees already collected
+    /// in a previous token stay with that token: creators get their credits back with
+    /// `withdraw_ft_fee_credit` and the fees are withdrawn with `withdraw_ft_treasury`.
+    pub fn set_ft_creation_fee(&mut self, ft_fee: Option<FtFee>) {
+        self.assert_owner();
+        self.ft_fee = ft_fee;
+    }
+
+    pub fn get_pool_creation_cost(&self) -> PoolCreationCost {
+        PoolCreationCost {
+            account_deposit: U128(POOL_ACCOUNT_DEPOSIT),
+            creation_fee: U128(self.creation_fee),
+            total: U128(POOL_ACCOUNT_DEPOSIT + self.creation_fee),
+            ft_fee: self.ft_fee.clone(),
+        }
+    }
+
+    /// The treasury account, the collected NEAR fees and the collected FT fees per token.
+    pub fn get_treasury(&self) -> (AccountId, U128, Vec<(AccountId, U128)>) {
+        let ft_treasury = self.ft_treasury.iter().map(|(token_id, balance)| (token_id, U128(balance))).collect();
+        (self.treasury_id.clone(), U128(self.treasury_balance), ft_treasury)
+    }
+
+    pub fn get_ft_fee_credit(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        U128(self.ft_fee_credits.get(&(token_id, account_id)).unwrap_or(0))
+    }
+
+    /// Sends collected NEAR fees to the treasury account, all of them by default.
+    pub fn withdraw_treasury(&mut self, amount: Option<U128>) -> Promise {
+        self.assert_owner();
+        let amount = amount.map(|amount| amount.0).unwrap_or(self.treasury_balance);
+        assert!(amount > 0 && amount <= self.treasury_balance, "not enough fees in the treasury");
+        self.treasury_balance -= amount;
+        Promise::new(self.treasury_id.clone()).transfer(amount)
+    }
+
+    /// Sends FT fees collected in `token_id` to the treasury account, all of them by default.
+    pub fn withdraw_ft_treasury(&mut self, token_id: ValidAccountId, amount: Option<U128>) -> Promise {
+        self.assert_owner();
+        let token_id: AccountId = token_id.into();
+        let balance = self.ft_treasury.get(&token_id).unwrap_or(0);
+        let amount = amount.map(|amount| amount.0).unwrap_or(balance);
+        assert!(amount > 0 && amount <= balance, "not enough fees in the treasury");
+        self.ft_treasury.insert(&token_id, &(balance - amount));
+        Promise::new(token_id.clone())
+            .function_call(
+                b"ft_transfer".to_vec(),
+                json!({"receiver_id": self.treasury_id, "amount": U128(amount)}).to_string().into_bytes(),
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::on_ft_treasury_withdrawn(
+                token_id,
+                U128(amount),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_ON_FT_WITHDRAWN,
+            ))
+    }
+
+    #[private]
+    pub fn on_ft_treasury_withdrawn(&mut self, token_id: AccountId, amount: U128) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("FT treasury withdrawal of {} {} failed", amount.0, token_id);
+                let balance = self.ft_treasury.get(&token_id).unwrap_or(0);
+                self.ft_treasury.insert(&token_id, &(balance + amount.0));
+                false
+            }
+        }
+    }
+
+    /// Sends the caller's unused FT fee credits in `token_id` back to them, e.g. after the fee
+    /// token changed.
+    pub fn withdraw_ft_fee_credit(&mut self, token_id: ValidAccountId) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let token_id: AccountId = token_id.into();
+        let amount = self.ft_fee_credits.remove(&(token_id.clone(), account_id.clone())).unwrap_or(0);
+        assert!(amount > 0, "no FT fee credits in {}", token_id);
+        Promise::new(token_id.clone())
+            .function_call(
+                b"ft_transfer".to_vec(),
+                json!({"receiver_id": account_id, "amount": U128(amount)}).to_string().into_bytes(),
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::on_ft_fee_credit_withdrawn(
+                account_id,
+                token_id,
+                U128(amount),
+                &env::current_account_id(),
+                0,
+                GAS_FOR_ON_FT_WITHDRAWN,
+            ))
+    }
+
+    #[private]
+    pub fn on_ft_fee_credit_withdrawn(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                log!("withdrawing {} {} of FT fee credits for {} failed", amount.0, token_id, account_id);
+                self.internal_add_ft_fee_credit(&token_id, &account_id, amount.0);
+                false
+            }
+        }
+    }
+
+    /// Latest pool deployed for `for_account`.
+    pub fn get_pool_details(&self, for_account : AccountId)-> Option<AccountId>{
+        return self.token.get(&for_account);
+    }
+
+    pub fn get_pool(&self, pool_id: AccountId) -> Option<PoolInfo> {
+        self.pools.get(&pool_id)
+    }
+
+    pub fn get_pool_count(&self) -> U64 {
+        U64(self.pools.len())
+    }
+
+    pub fn get_pools(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<PoolInfo> {
+        let values = self.pools.values_as_vector();
+        let start = from_index.map(|index| index.0).unwrap_or(0);
+        let end = start.saturating_add(limit.unwrap_or(u64::MAX)).min(values.len());
+        (start..end).filter_map(|index| values.get(index)).collect()
+    }
+
+    pub fn get_pools_by_owner(&self, owner_id: AccountId) -> Vec<PoolInfo> {
+        self.pools_by_owner
+            .get(&owner_id)
+            .map(|pool_ids| pool_ids.iter().filter_map(|pool_id| self.pools.get(&pool_id)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Resolves `new_pool` and `operator_new_pool`. On success the pool becomes active and the
+    /// fees go to the treasury. On failure the registry entry is removed, the deposit is
+    /// refunded to the payer, the FT fee is credited back to them and the NFT contract is told
+    /// to release the escrowed owner NFT, if there is one.
+    #[private]
+    pub fn on_pool_created(&mut self, pool_id: AccountId, payment: PoolPayment) -> Option<AccountId> {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "This is a callback method"
+        );
+        let mut info = self.pools.get(&pool_id).expect("pool not found");
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                info.status = PoolStatus::Active;
+                self.pools.insert(&pool_id, &info);
+                self.treasury_balance += payment.fee.0;
+                if let Some(ft_fee) = payment.ft_fee {
+                    let balance = self.ft_treasury.get(&ft_fee.token_id).unwrap_or(0);
+                    self.ft_treasury.insert(&ft_fee.token_id, &(balance + ft_fee.amount.0));
+                }
+                Some(pool_id)
+            }
+            PromiseResult::Failed => {
+                log!("creating pool {} failed, refunding {}", pool_id, payment.payer);
+                self.unregister_pool(&info);
+                Promise::new(payment.payer.clone()).transfer(payment.deposit.0);
+                if let Some(ft_fee) = payment.ft_fee {
+                    self.internal_add_ft_fee_credit(&ft_fee.token_id, &payment.payer, ft_fee.amount.0);
+                }
+                if info.nft_escrowed {
+                    ext_nft::release_escrowed_nft(info.creator, &info.nft_contract, 0, GAS_FOR_RELEASE_NFT);
+                }
+                None
+            }
+        }
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(env::predecessor_account_id(), self.owner, "only the factory owner can call this method");
+    }
+
+    /// The owner counts as an operator too.
+    fn is_operator(&self, account_id: &AccountId) -> bool {
+        *account_id == self.owner || self.operators.contains(account_id)
+    }
+
+    /// Takes the FT creation fee out of `account_id`'s credits in the fee token and returns
+    /// what was taken.
+    fn take_ft_fee(&mut self, account_id: &AccountId) -> Option<FtFee> {
+        let ft_fee = self.ft_fee.clone()?;
+        let key = (ft_fee.token_id.clone(), account_id.clone());
+        let credit = self.ft_fee_credits.get(&key).unwrap_or(0);
+        assert!(credit >= ft_fee.amount.0, "{} has to send {} more of the FT creation fee first", account_id, ft_fee.amount.0 - credit);
+        self.ft_fee_credits.insert(&key, &(credit - ft_fee.amount.0));
+        Some(ft_fee)
+    }
+
+    fn internal_add_ft_fee_credit(&mut self, token_id: &AccountId, account_id: &AccountId, amount: Balance) {
+        let key = (token_id.clone(), account_id.clone());
+        let credit = self.ft_fee_credits.get(&key).unwrap_or(0);
+        self.ft_fee_credits.insert(&key, &(credit + amount));
     }
-}
-#[near_bindgen]
-impl DeployPool for Pool{
-    fn new_pool(&mut self, poolname: AccountId, owner_id :AccountId, roomsize: U128) -> PromiseOrValue<AccountId> {
+
+    /// Deploys the pool subaccount and registers the pool. The pool token lets `nft_contract`
+    /// move tokens on behalf of its holders. `payer` pays the FT fee and gets a refund if the
+    /// pool can't be created.
+    fn internal_new_pool(&mut self, pool: NewPool, nft_contract: AccountId, nft_escrowed: bool, payer: AccountId) -> Promise {
+        let NewPool { poolname, owner_id, roomsize, metadata, code_version, owner_vesting } = pool;
+        let deposit = env::attached_deposit();
+        let fee = self.creation_fee;
+        assert!(
+            deposit >= POOL_ACCOUNT_DEPOSIT + fee,
+            "Attach at least {} yoctoNEAR to create a pool",
+            POOL_ACCOUNT_DEPOSIT + fee
+        );
+        let ft_fee = self.take_ft_fee(&payer);
+
+        metadata.assert_valid();
+        let code_version = code_version
+            .or(self.default_code_version)
+            .expect("no pool code has been stored yet");
+        let code = self.pool_code.get(&code_version).expect("code version not found");
 
         log!("{}",env::prepaid_gas().to_string());
         let subaccount_id = format!("{}.{}", poolname, env::current_account_id()).to_string();
-        let stuff =Promise::new(subaccount_id.clone())
-            .create_account()
-            .add_full_access_key(env::signer_account_pk())
-            .transfer(NO_DEPOSIT)
-            .deploy_contract(CODE.to_vec());
-
-        env::log("this was here".to_string().as_bytes());
         log!("creating nft pool for nep141contract");
 
         let ownervalid =ValidAccountId::try_from(owner_id.clone()).unwrap();
         log!("{}",ownervalid.to_string());
-        log!("{}",json!({"owner_id":ownervalid,"name":"newname","total_supply":roomsize,"nftcaller":"nftcontract.somenewname.testnet"}).to_string());
+        let init_args = json!({
+            "owner_id": ownervalid,
+            "total_supply": roomsize,
+            "metadata": metadata,
+            "nftcaller": nft_contract,
+            "owner_vesting": owner_vesting,
+        }).to_string();
+        log!("{}",init_args);
 
-        let otherpromise=Promise::new(subaccount_id.clone()).function_call(
-            b"new_default_meta".to_vec(),
-            json!({"owner_id":ownervalid,"name":"newname","total_supply":roomsize,"nftcaller":"nftcontract.somenewname.testnet"}).to_string().into_bytes(),
-0,
-            5_000_000_000_000
+        // Initialization runs in the same receipt as the account creation, so a failed init
+        // rolls back the whole subaccount and the deposit comes back to the factory.
+        let mut stuff =Promise::new(subaccount_id.clone())
+            .create_account()
+            .transfer(deposit - fee)
+            .deploy_contract(code);
+        if let PoolKeyPolicy::FunctionCallKey { public_key, method_names } = &self.pool_key_policy {
+            stuff = stuff.add_access_key(
+                public_key.clone().into(),
+                POOL_ADMIN_KEY_ALLOWANCE,
+                subaccount_id.clone(),
+                method_names.as_bytes().to_vec(),
+            );
+        }
+        let stuff = stuff.function_call(
+            b"new".to_vec(),
+            init_args.into_bytes(),
+            0,
+            GAS_FOR_POOL_INIT
         );
 
+        self.token.insert(&owner_id.clone(),&subaccount_id);
+        self.register_pool(PoolInfo {
+            name: poolname,
+            ft_contract: subaccount_id.clone(),
+            creator: owner_id,
+            nft_contract: nft_contract.clone(),
+            nft_escrowed,
+            created_at: U64(env::block_timestamp()),
+            room_size: roomsize,
+            status: PoolStatus::Pending,
+            key_policy: self.pool_key_policy.clone(),
+            code_version,
+            last_upgrade: None,
+        });
 
-        stuff.then(otherpromise);
+        stuff.then(ext_self::on_pool_created(
+            subaccount_id,
+            PoolPayment { payer, deposit: U128(deposit), fee: U128(fee), ft_fee },
+            &env::current_account_id(),
+            0,
+            GAS_FOR_ON_POOL_CREATED
+        ))
+    }
 
-        self.token.insert(&owner_id.clone(),&subaccount_id);
+    fn register_pool(&mut self, info: PoolInfo) {
+        if let Some(existing) = self.pools.get(&info.ft_contract) {
+            assert_eq!(existing.status, PoolStatus::Closed, "pool {} already exists", info.ft_contract);
+            if let Some(mut owner_pools) = self.pools_by_owner.get(&existing.creator) {
+                owner_pools.remove(&info.ft_contract);
+                self.pools_by_owner.insert(&existing.creator, &owner_pools);
+            }
+        }
+        self.pools.insert(&info.ft_contract, &info);
 
-        return PromiseOrValue::Value(subaccount_id);
+        let mut owner_pools = self.pools_by_owner.get(&info.creator).unwrap_or_else(|| {
+            let mut prefix = b"v".to_vec();
+            prefix.extend(env::sha256(info.creator.as_bytes()));
+            UnorderedSet::new(prefix)
+        });
+        owner_pools.insert(&info.ft_contract);
+        self.pools_by_owner.insert(&info.creator, &owner_pools);
+    }
+
+    fn unregister_pool(&mut self, info: &PoolInfo) {
+        self.pools.remove(&info.ft_contract);
+        if let Some(mut owner_pools) = self.pools_by_owner.get(&info.creator) {
+            owner_pools.remove(&info.ft_contract);
+            self.pools_by_owner.insert(&info.creator, &owner_pools);
+        }
+        if self.token.get(&info.creator).as_ref() == Some(&info.ft_contract) {
+            self.token.remove(&info.creator);
+        }
     }
 }
+#[near_bindgen]
+impl DeployPool for Pool{
+    /// Deploys a pool token contract on a new subaccount. Only authorized callers may call
+    /// this. The attached deposit must cover `POOL_ACCOUNT_DEPOSIT` plus the creation fee; all
+    /// of it except the fee funds the subaccount.
+    /// The pool token is initialized with `metadata` and `roomsize` as its total supply.
+    /// With `owner_vesting`, that part of the supply vests to `owner_id` instead.
+    /// `owner_id` pays through the NFT contract, so the FT fee comes from their credits and
+    /// refunds go to them.
+    #[payable]
+    fn new_pool(&mut self, poolname: AccountId, owner_id :AccountId, roomsize: U128, metadata: FungibleTokenMetadata, code_version: Option<u32>, owner_vesting: Option<VestingSchedule>) -> PromiseOrValue<Option<AccountId>> {
+        assert!(
+            self.authorized_callers.contains(&env::predecessor_account_id()),
+            "{} is not allowed to create pools",
+            env::predecessor_account_id()
+        );
+        let payer = owner_id.clone();
+        let pool = NewPool { poolname, owner_id, roomsize, metadata, code_version, owner_vesting };
+        PromiseOrValue::Promise(self.internal_new_pool(pool, env::predecessor_account_id(), true, payer))
+    }
+}
+
+/// Accepts the platform FT towards the FT creation fee of `sender_id`. Anything above the
+/// outstanding fee is returned to the sender.
+#[near_bindgen]
+impl FungibleTokenReceiver for Pool {
+    fn ft_on_transfer(&mut self, sender_id: ValidAccountId, amount: U128, _msg: String) -> PromiseOrValue<U128> {
+        let ft_fee = match &self.ft_fee {
+            Some(ft_fee) if ft_fee.token_id == env::predecessor_account_id() => ft_fee.clone(),
+            _ => return PromiseOrValue::Value(amount),
+        };
+        log!("received {} towards the pool creation fee from {}", amount.0, sender_id);
+        let credit = self.ft_fee_credits.get(&(ft_fee.token_id.clone(), sender_id.clone().into())).unwrap_or(0);
+        let accepted = ft_fee.amount.0.saturating_sub(credit).min(amount.0);
+        self.internal_add_ft_fee_credit(&ft_fee.token_id, sender_id.as_ref(), accepted);
+        PromiseOrValue::Value(U128(amount.0 - accepted))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::MockedBlockchain;
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    const CREATION_FEE: Balance = 1_000;
+
+    fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn pool_metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "pool".to_string(),
+            symbol: "POOL".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 0,
+        }
+    }
+
+    /// A factory owned by `accounts(1)` with pool code stored, `accounts(3)` as the NFT
+    /// contract and a FT fee of 10 in `accounts(5)`, which `accounts(4)` has already sent.
+    fn setup_contract(context: &mut VMContextBuilder) -> Pool {
+        testing_env!(context.build());
+        let mut contract = Pool::new(accounts(2));
+        contract.add_authorized_caller(accounts(3));
+        contract.set_creation_fee(U128(CREATION_FEE));
+        contract.set_ft_creation_fee(Some(FtFee { token_id: accounts(5).into(), amount: U128(10) }));
+        let mut store_context = context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(10_000_000_000_000_000_000_000_000)
+            .build();
+        store_context.input = b"pool code".to_vec();
+        testing_env!(store_context);
+        contract.store_pool_code();
+
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(0).predecessor_account_id(accounts(5)).build());
+        contract.ft_on_transfer(accounts(4), U128(10), String::new());
+        contract
+    }
+
+    /// Creates the pool of `accounts(4)` through the NFT contract and returns its id.
+    fn create_pool(context: &mut VMContextBuilder, contract: &mut Pool) -> AccountId {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(POOL_ACCOUNT_DEPOSIT + CREATION_FEE)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.new_pool("room".to_string(), accounts(4).into(), U128(100), pool_metadata(), None, None);
+        format!("room.{}", accounts(0))
+    }
+
+    /// What `payer` pays for a pool in these tests.
+    fn payment(payer: ValidAccountId) -> PoolPayment {
+        PoolPayment {
+            payer: payer.into(),
+            deposit: U128(POOL_ACCOUNT_DEPOSIT + CREATION_FEE),
+            fee: U128(CREATION_FEE),
+            ft_fee: Some(FtFee { token_id: accounts(5).into(), amount: U128(10) }),
+        }
+    }
+
+    fn callback(context: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            context.storage_usage(env::storage_usage()).attached_deposit(0).predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result]
+        );
+    }
+
+    #[test]
+    fn test_on_pool_created_success() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = create_pool(&mut context, &mut contract);
+        assert_eq!(contract.get_pool(pool_id.clone()).unwrap().status, PoolStatus::Pending);
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 0);
+
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        let result = contract.on_pool_created(pool_id.clone(), payment(accounts(4)));
+        assert_eq!(result, Some(pool_id.clone()));
+        let info = contract.get_pool(pool_id.clone()).unwrap();
+        assert_eq!(info.status, PoolStatus::Active);
+        assert!(info.nft_escrowed);
+        let (_, treasury, ft_treasury) = contract.get_treasury();
+        assert_eq!(treasury.0, CREATION_FEE);
+        assert_eq!(ft_treasury, vec![(accounts(5).to_string(), U128(10))]);
+        assert_eq!(contract.get_pool_details(accounts(4).into()), Some(pool_id));
+    }
+
+    #[test]
+    fn test_on_pool_created_failure_refunds_fees() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = create_pool(&mut context, &mut contract);
+
+        callback(&mut context, PromiseResult::Failed);
+        let result = contract.on_pool_created(pool_id.clone(), payment(accounts(4)));
+        assert_eq!(result, None);
+        assert!(contract.get_pool(pool_id).is_none());
+        assert!(contract.get_pools_by_owner(accounts(4).into()).is_empty());
+        assert_eq!(contract.get_pool_details(accounts(4).into()), None);
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 10);
+        let (_, treasury, ft_treasury) = contract.get_treasury();
+        assert_eq!(treasury.0, 0);
+        assert!(ft_treasury.is_empty());
+    }
+
+    #[test]
+    fn test_on_pool_closed() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = create_pool(&mut context, &mut contract);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        contract.on_pool_created(pool_id.clone(), payment(accounts(4)));
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(4)).build());
+        contract.close_pool(pool_id.clone(), None);
+        assert_eq!(contract.get_pool(pool_id.clone()).unwrap().status, PoolStatus::Closing);
+        callback(&mut context, PromiseResult::Failed);
+        assert!(!contract.on_pool_closed(pool_id.clone()));
+        assert_eq!(contract.get_pool(pool_id.clone()).unwrap().status, PoolStatus::Active);
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(4)).build());
+        contract.close_pool(pool_id.clone(), None);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        assert!(contract.on_pool_closed(pool_id.clone()));
+        assert_eq!(contract.get_pool(pool_id).unwrap().status, PoolStatus::Closed);
+        assert_eq!(contract.get_pool_details(accounts(4).into()), None);
+    }
+
+    #[test]
+    fn test_store_pool_code_refunds_excess_deposit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let deposit = 10_000_000_000_000_000_000_000_000;
+        let balance = 100_000_000_000_000_000_000_000_000;
+        let mut store_context = context
+            .storage_usage(env::storage_usage())
+            .account_balance(balance)
+            .attached_deposit(deposit)
+            .predecessor_account_id(accounts(1))
+            .build();
+        store_context.input = b"newer pool code".to_vec();
+        testing_env!(store_context);
+        let initial_storage_usage = env::storage_usage();
+        assert_eq!(contract.store_pool_code(), 1);
+
+        let storage_cost = Balance::from(env::storage_usage() - initial_storage_usage) * env::storage_byte_cost();
+        assert_eq!(balance + deposit - env::account_balance(), deposit - storage_cost);
+    }
 
+    #[test]
+    fn test_closed_pool_id_moves_to_new_creator() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = create_pool(&mut context, &mut contract);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        contract.on_pool_created(pool_id.clone(), payment(accounts(4)));
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(4)).build());
+        contract.close_pool(pool_id.clone(), None);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        contract.on_pool_closed(pool_id.clone());
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(5)).build());
+        contract.ft_on_transfer(accounts(2), U128(10), String::new());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(POOL_ACCOUNT_DEPOSIT + CREATION_FEE)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.new_pool("room".to_string(), accounts(2).into(), U128(100), pool_metadata(), None, None);
+
+        assert!(contract.get_pools_by_owner(accounts(4).into()).is_empty());
+        assert_eq!(contract.get_pools_by_owner(accounts(2).into())[0].ft_contract, pool_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not allowed to create pools")]
+    fn test_new_pool_only_authorized_callers() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(POOL_ACCOUNT_DEPOSIT + CREATION_FEE)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.new_pool("room".to_string(), accounts(4).into(), U128(100), pool_metadata(), None, None);
+    }
+
+    /// `accounts(2)`, an operator, creates a pool for `accounts(4)` and pays its FT fee.
+    fn operator_create_pool(context: &mut VMContextBuilder, contract: &mut Pool) -> AccountId {
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(5)).build());
+        contract.ft_on_transfer(accounts(2), U128(10), String::new());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(POOL_ACCOUNT_DEPOSIT + CREATION_FEE)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.operator_new_pool("room".to_string(), accounts(4), U128(100), pool_metadata(), None, accounts(3), None);
+        format!("room.{}", accounts(0))
+    }
+
+    #[test]
+    fn test_new_pool_passes_owner_vesting() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(POOL_ACCOUNT_DEPOSIT + CREATION_FEE)
+            .predecessor_account_id(accounts(3))
+            .build());
+        let vesting = VestingSchedule { amount: U128(60), start: U64(0), cliff: U64(100), end: U64(1_000) };
+        contract.new_pool("room".to_string(), accounts(4).into(), U128(100), pool_metadata(), None, Some(vesting));
+
+        let init_args = get_logs().into_iter().find(|log| log.contains("\"total_supply\"")).unwrap();
+        let init_args: near_sdk::serde_json::Value = near_sdk::serde_json::from_str(&init_args).unwrap();
+        assert_eq!(
+            init_args["owner_vesting"],
+            json!({ "amount": "60", "start": "0", "cliff": "100", "end": "1000" })
+        );
+    }
+
+    #[test]
+    fn test_operator_new_pool_has_no_escrow() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = operator_create_pool(&mut context, &mut contract);
+        let info = contract.get_pool(pool_id).unwrap();
+        assert_eq!(info.creator, accounts(4).to_string());
+        assert_eq!(info.nft_contract, accounts(3).to_string());
+        assert!(!info.nft_escrowed);
+        assert_eq!(contract.get_ft_fee_credit(accounts(2).into(), accounts(5).into()).0, 0);
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 10);
+    }
+
+    #[test]
+    fn test_failed_operator_pool_refunds_the_operator() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = operator_create_pool(&mut context, &mut contract);
+
+        callback(&mut context, PromiseResult::Failed);
+        assert_eq!(contract.on_pool_created(pool_id, payment(accounts(2))), None);
+        let receipts = near_sdk::serde_json::to_string(&near_sdk::test_utils::get_created_receipts()).unwrap();
+        assert!(receipts.contains(&format!("\"receiver_id\":\"{}\"", accounts(2))));
+        assert!(!receipts.contains(&format!("\"receiver_id\":\"{}\"", accounts(4))));
+        assert_eq!(contract.get_ft_fee_credit(accounts(2).into(), accounts(5).into()).0, 10);
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 10);
+    }
+
+    #[test]
+    fn test_on_ft_treasury_withdrawn_failure_restores_balance() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = create_pool(&mut context, &mut contract);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        contract.on_pool_created(pool_id, payment(accounts(4)));
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        contract.withdraw_ft_treasury(accounts(5), Some(U128(4)));
+        assert_eq!(contract.get_treasury().2, vec![(accounts(5).to_string(), U128(6))]);
+        callback(&mut context, PromiseResult::Failed);
+        assert!(!contract.on_ft_treasury_withdrawn(accounts(5).into(), U128(4)));
+        assert_eq!(contract.get_treasury().2, vec![(accounts(5).to_string(), U128(10))]);
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        contract.withdraw_ft_treasury(accounts(5), None);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        assert!(contract.on_ft_treasury_withdrawn(accounts(5).into(), U128(10)));
+        assert_eq!(contract.get_treasury().2, vec![(accounts(5).to_string(), U128(0))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "has to send 10 more of the FT creation fee first")]
+    fn test_credits_do_not_pay_fees_in_another_token() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_ft_creation_fee(Some(FtFee { token_id: accounts(2).into(), amount: U128(10) }));
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 10);
+        create_pool(&mut context, &mut contract);
+    }
+
+    #[test]
+    fn test_withdraw_ft_fee_credit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_ft_creation_fee(None);
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(4)).build());
+        contract.withdraw_ft_fee_credit(accounts(5));
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 0);
+        callback(&mut context, PromiseResult::Failed);
+        assert!(!contract.on_ft_fee_credit_withdrawn(accounts(4).into(), accounts(5).into(), U128(10)));
+        assert_eq!(contract.get_ft_fee_credit(accounts(4).into(), accounts(5).into()).0, 10);
+    }
+
+    #[test]
+    fn test_on_pool_upgraded() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_contract(&mut context);
+        let pool_id = create_pool(&mut context, &mut contract);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        contract.on_pool_created(pool_id.clone(), payment(accounts(4)));
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        contract.upgrade_pools(vec![pool_id.clone()], 0);
+        callback(&mut context, PromiseResult::Failed);
+        assert!(!contract.on_pool_upgraded(pool_id.clone(), 0));
+        assert_eq!(contract.get_pool(pool_id.clone()).unwrap().last_upgrade.unwrap().status, UpgradeStatus::Failed);
+
+        testing_env!(context.storage_usage(env::storage_usage()).predecessor_account_id(accounts(1)).build());
+        contract.upgrade_pools(vec![pool_id.clone()], 0);
+        callback(&mut context, PromiseResult::Successful(vec![]));
+        assert!(contract.on_pool_upgraded(pool_id.clone(), 0));
+        assert_eq!(contract.get_pool(pool_id).unwrap().last_upgrade.unwrap().status, UpgradeStatus::Upgraded);
+    }
+}
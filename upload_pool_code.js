@@ -0,0 +1,41 @@
+// Uploads a pool token wasm to the pool factory with `store_pool_code`.
+//
+// The code goes in as the raw input of the call. A base64 wasm is too big to be passed as a
+// command line argument to `near call`, so this reads it from the file instead. Uses the keys
+// near-cli keeps in ~/.near-credentials. Needs `npm install near-api-js`.
+//
+//   node upload_pool_code.js <factory account> <wasm file> [deposit in NEAR]
+const fs = require("fs");
+const os = require("os");
+const path = require("path");
+const { connect, keyStores, utils } = require("near-api-js");
+
+async function main() {
+  const [factoryId, wasmFile, deposit = "3"] = process.argv.slice(2);
+  if (!factoryId || !wasmFile) {
+    console.error("usage: node upload_pool_code.js <factory account> <wasm file> [deposit in NEAR]");
+    process.exit(1);
+  }
+  const networkId = process.env.NEAR_ENV || "testnet";
+  const near = await connect({
+    networkId,
+    nodeUrl: `https://rpc.${networkId}.near.org`,
+    keyStore: new keyStores.UnencryptedFileSystemKeyStore(path.join(os.homedir(), ".near-credentials")),
+  });
+  const account = await near.account(factoryId);
+  // A Buffer is passed to the contract as is, not as JSON.
+  const outcome = await account.functionCall({
+    contractId: factoryId,
+    methodName: "store_pool_code",
+    args: fs.readFileSync(wasmFile),
+    gas: "300000000000000",
+    attachedDeposit: utils.format.parseNearAmount(deposit),
+  });
+  const version = Buffer.from(outcome.status.SuccessValue, "base64").toString();
+  console.log(`stored ${wasmFile} as pool code version ${version}`);
+}
+
+main().catch((error) => {
+  console.error(error);
+  process.exit(1);
+});